@@ -0,0 +1,304 @@
+//! Reading of attribute-based XML exports, shared by the Rekordbox and Traktor readers.
+//!
+//! Both formats keep a track's fields in element attributes rather than plist keys, so values
+//! are parsed from attribute strings and errors point at the attribute by name.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use xml::attribute::OwnedAttribute;
+use xml::common::Position;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::error::{Context, ErrorLocation};
+use crate::{CountingReader, Diagnostic, ParseError, ParseReport, Progress};
+
+pub(crate) struct AttributeReader<R: Read> {
+    parser: EventReader<BufReader<CountingReader<R>>>,
+    total_bytes: Option<u64>,
+    pub(crate) context: Context,
+    lenient: bool,
+    pub(crate) report: ParseReport,
+}
+
+impl AttributeReader<File> {
+    pub(crate) fn open(file_path: &str, lenient: bool) -> Result<Self, ParseError> {
+        let file = File::open(file_path)?;
+        let total_bytes = file.metadata().ok().map(|metadata| metadata.len());
+        Ok(AttributeReader::new(file, total_bytes, lenient))
+    }
+}
+
+impl<R: Read> AttributeReader<R> {
+    pub(crate) fn new(reader: R, total_bytes: Option<u64>, lenient: bool) -> Self {
+        let reader = BufReader::new(CountingReader {
+            inner: reader,
+            bytes_read: 0,
+        });
+        AttributeReader {
+            parser: EventReader::new(reader),
+            total_bytes,
+            context: Context::Document,
+            lenient,
+            report: ParseReport::default(),
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> Result<XmlEvent, ParseError> {
+        self.parser.next().map_err(|err| {
+            let position = err.position();
+            ParseError::Xml {
+                message: err.msg().to_string(),
+                at: ErrorLocation {
+                    line: position.row + 1,
+                    column: position.column + 1,
+                    context: self.context.clone(),
+                },
+            }
+        })
+    }
+
+    pub(crate) fn integer(
+        &mut self,
+        attributes: &[OwnedAttribute],
+        key: &str,
+    ) -> Result<Option<i64>, ParseError> {
+        match attribute(attributes, key).filter(|value| !value.is_empty()) {
+            None => Ok(None),
+            Some(value) => match value.trim().parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(ParseError::BadInteger {
+                    value: value.to_string(),
+                    at: self.location_at(key),
+                }),
+            },
+        }
+    }
+
+    /// An integer attribute multiplied by `factor`, a bad integer when that overflows.
+    pub(crate) fn scaled_integer(
+        &mut self,
+        attributes: &[OwnedAttribute],
+        key: &str,
+        factor: i64,
+    ) -> Result<Option<i64>, ParseError> {
+        let Some(value) = self.integer(attributes, key)? else {
+            return Ok(None);
+        };
+        match value.checked_mul(factor) {
+            Some(scaled) => Ok(Some(scaled)),
+            None => Err(ParseError::BadInteger {
+                value: value.to_string(),
+                at: self.location_at(key),
+            }),
+        }
+    }
+
+    pub(crate) fn real(
+        &mut self,
+        attributes: &[OwnedAttribute],
+        key: &str,
+    ) -> Result<Option<f64>, ParseError> {
+        match attribute(attributes, key).filter(|value| !value.is_empty()) {
+            None => Ok(None),
+            Some(value) => match value.trim().parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(ParseError::BadReal {
+                    value: value.to_string(),
+                    at: self.location_at(key),
+                }),
+            },
+        }
+    }
+
+    /// A calendar date in `format`, taken as midnight UTC.
+    pub(crate) fn date(
+        &mut self,
+        attributes: &[OwnedAttribute],
+        key: &str,
+        format: &str,
+    ) -> Result<Option<DateTime<Utc>>, ParseError> {
+        match attribute(attributes, key).filter(|value| !value.is_empty()) {
+            None => Ok(None),
+            Some(value) => match NaiveDate::parse_from_str(value.trim(), format) {
+                Ok(date) => Ok(date.and_hms_opt(0, 0, 0).map(|date| date.and_utc())),
+                Err(_) => Err(ParseError::BadDate {
+                    value: value.to_string(),
+                    at: self.location_at(key),
+                }),
+            },
+        }
+    }
+
+    /// Counts a track that could not be read as skipped when parsing leniently.
+    pub(crate) fn skip_track(&mut self, err: ParseError) -> Result<(), ParseError> {
+        match self.lenient && err.is_recoverable() {
+            true => {
+                self.report.skipped_tracks += 1;
+                self.report.diagnostics.push(Diagnostic::skipped(&err));
+                Ok(())
+            }
+            false => Err(err),
+        }
+    }
+
+    /// Drops a part of a track that could not be read, keeping the track when parsing
+    /// leniently.
+    pub(crate) fn skip_marker(&mut self, err: ParseError) -> Result<(), ParseError> {
+        match self.lenient && err.is_recoverable() {
+            true => {
+                let diagnostic = Diagnostic {
+                    skipped: false,
+                    ..Diagnostic::skipped(&err)
+                };
+                self.report.diagnostics.push(diagnostic);
+                Ok(())
+            }
+            false => Err(err),
+        }
+    }
+
+    /// Records a non-fatal problem in the report, in strict mode as well.
+    pub(crate) fn warn(&mut self, reason: String) {
+        let diagnostic = Diagnostic::warning(&self.location(), reason);
+        self.report.diagnostics.push(diagnostic);
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        Progress {
+            bytes_read: self.parser.source().get_ref().bytes_read,
+            total_bytes: self.total_bytes,
+        }
+    }
+
+    pub(crate) fn location_at(&mut self, attribute: &str) -> ErrorLocation {
+        if let Context::Track { key, .. } = &mut self.context {
+            *key = Some(attribute.to_string());
+        }
+        self.location()
+    }
+
+    pub(crate) fn location(&self) -> ErrorLocation {
+        let position = self.parser.position();
+        ErrorLocation {
+            line: position.row + 1,
+            column: position.column + 1,
+            context: self.context.clone(),
+        }
+    }
+}
+
+/// Persistent IDs for playlist nodes of formats without them, hashed from the names of the
+/// node and its folders so they stay the same across exports of one collection. `prefix` tells
+/// the formats apart; nodes sharing a path are numbered in document order.
+pub(crate) struct NodeIds {
+    prefix: &'static str,
+    seen: HashMap<u64, u64>,
+}
+
+impl NodeIds {
+    pub(crate) fn new(prefix: &'static str) -> Self {
+        NodeIds {
+            prefix,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn next<'a>(&mut self, path: impl IntoIterator<Item = &'a str>) -> String {
+        let mut hash = FNV_OFFSET;
+        for name in path {
+            hash = fnv(hash, name.as_bytes());
+            hash = fnv(hash, &[0]);
+        }
+        let occurrence = self.seen.entry(hash).or_default();
+        *occurrence += 1;
+        let hash = fnv(hash, &occurrence.to_be_bytes());
+        format!("{}{:016X}", self.prefix, hash)
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// FNV-1a, which unlike the std hasher stays the same across releases.
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub(crate) fn attribute<'a>(attributes: &'a [OwnedAttribute], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|attribute| attribute.name.local_name == key)
+        .map(|attribute| attribute.value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_bad_values_at_their_attribute() {
+        let xml = r#"<TRACK Size="big" Rating="255" Length="9223372036854775807"/>"#;
+        let mut reader = AttributeReader::new(xml.as_bytes(), None, true);
+        let attributes = loop {
+            if let XmlEvent::StartElement { attributes, .. } = reader.next().unwrap() {
+                break attributes;
+            }
+        };
+        reader.context = Context::Track {
+            id: Some(1),
+            key: None,
+        };
+
+        assert_eq!(
+            reader.scaled_integer(&attributes, "Rating", 100).unwrap(),
+            Some(25_500)
+        );
+        assert_eq!(reader.integer(&attributes, "Missing").unwrap(), None);
+        let err = reader.integer(&attributes, "Size").unwrap_err();
+        let context = Context::Track {
+            id: Some(1),
+            key: Some("Size".to_string()),
+        };
+        assert_eq!(err.location().unwrap().context, context);
+        let err = reader
+            .scaled_integer(&attributes, "Length", 1000)
+            .unwrap_err();
+        assert!(matches!(err, ParseError::BadInteger { .. }));
+        assert!(reader.date(&attributes, "Size", "%Y-%m-%d").is_err());
+    }
+
+    #[test]
+    fn derives_node_ids_from_paths() {
+        let mut ids = NodeIds::new("RB");
+        let first = ids.next(["Gigs", "Club X"]);
+        let duplicate = ids.next(["Gigs", "Club X"]);
+
+        assert!(first.starts_with("RB"));
+        assert_ne!(first, duplicate);
+        assert_ne!(first, ids.next(["Gigs"]));
+        assert_eq!(NodeIds::new("RB").next(["Gigs", "Club X"]), first);
+        assert_ne!(NodeIds::new("TK").next(["Gigs", "Club X"]), first);
+    }
+
+    #[test]
+    fn skips_only_when_lenient() {
+        let err = || ParseError::BadInteger {
+            value: "big".to_string(),
+            at: ErrorLocation::default(),
+        };
+        let mut lenient = AttributeReader::new(&b""[..], None, true);
+        let mut strict = AttributeReader::new(&b""[..], None, false);
+
+        assert!(lenient.skip_track(err()).is_ok());
+        assert!(lenient.skip_marker(err()).is_ok());
+        assert!(strict.skip_track(err()).is_err());
+        assert!(strict.skip_marker(err()).is_err());
+        assert_eq!(lenient.report.skipped_tracks, 1);
+        assert!(lenient.report.diagnostics[0].skipped);
+        assert!(!lenient.report.diagnostics[1].skipped);
+    }
+}
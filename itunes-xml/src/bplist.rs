@@ -0,0 +1,391 @@
+//! Reading of binary property lists (`bplist00`).
+//!
+//! The whole object table is decoded up front into the same sequence of elements the XML
+//! reader produces, so tracks and playlists go through the regular parser:
+//!
+//! ```text
+//! "bplist00" | objects | offset table | trailer (32 bytes)
+//! trailer: 6 unused, offset size (u8), reference size (u8), object count (u64),
+//!          top object (u64), offset table offset (u64)
+//! object: marker byte, high nibble type, low nibble size (0xF: an integer object follows)
+//! ```
+
+use std::vec;
+
+use crate::dates::from_plist_seconds;
+use crate::error::ErrorLocation;
+use crate::{Element, ParseError};
+
+pub(crate) const MAGIC: &[u8] = b"bplist00";
+const TRAILER_LENGTH: usize = 32;
+/// Guards against reference cycles in malformed files.
+const MAX_DEPTH: usize = 64;
+/// Decoded tokens allowed per byte of input. Every reference takes at least a byte and yields
+/// at most two tokens, unless containers are shared, which can expand exponentially.
+const TOKENS_PER_BYTE: usize = 2;
+/// Decoded string and data bytes allowed per byte of input. Shared keys and values are copied
+/// for every reference, a few times the file at most in real libraries.
+const DECODED_BYTES_PER_BYTE: usize = 64;
+
+pub(crate) enum Token {
+    Element(Element),
+    /// End of a dict, an array or the plist itself.
+    End,
+    /// A value without a counterpart in iTunes libraries, e.g. `<real>`.
+    Unsupported(&'static str),
+}
+
+/// Decoded elements, each with the byte offset of its object.
+pub(crate) struct Tokens {
+    tokens: vec::IntoIter<(usize, Token)>,
+    count: usize,
+    offset: usize,
+    length: u64,
+}
+
+impl Tokens {
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        let mut decoder = Decoder::new(data)?;
+        decoder.tokens.push((0, Token::Element(Element::Plist)));
+        decoder.object(decoder.top_object, 0)?;
+        decoder.tokens.push((data.len(), Token::End));
+        Ok(Tokens {
+            count: decoder.tokens.len(),
+            tokens: decoder.tokens.into_iter(),
+            offset: 0,
+            length: data.len() as u64,
+        })
+    }
+
+    pub(crate) fn next(&mut self) -> Option<Token> {
+        let (offset, token) = self.tokens.next()?;
+        self.offset = offset;
+        Some(token)
+    }
+
+    /// Share of the file read, as the returned tokens spread over its length. Objects are not
+    /// stored in document order, so their offsets do not tell.
+    pub(crate) fn bytes_read(&self) -> u64 {
+        let returned = (self.count - self.tokens.len()) as u64;
+        self.length * returned / self.count as u64
+    }
+
+    /// Byte offset of the object behind the last returned token.
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    ref_size: usize,
+    offsets: Vec<usize>,
+    top_object: u64,
+    tokens: Vec<(usize, Token)>,
+    max_tokens: usize,
+    decoded_bytes: usize,
+    max_decoded_bytes: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, ParseError> {
+        if !data.starts_with(MAGIC) || data.len() < MAGIC.len() + TRAILER_LENGTH {
+            return Err(error("missing bplist00 header or trailer", 0));
+        }
+        let trailer_offset = data.len() - TRAILER_LENGTH;
+        let trailer = &data[trailer_offset..];
+        let offset_size = trailer[6] as usize;
+        let ref_size = trailer[7] as usize;
+        let object_count = read_uint(trailer, 8, 8);
+        let top_object = read_uint(trailer, 16, 8);
+        let table_offset = read_uint(trailer, 24, 8);
+        if !(1..=8).contains(&offset_size) || !(1..=8).contains(&ref_size) {
+            return Err(error("bad offset or reference size", trailer_offset));
+        }
+
+        let table_length = object_count.checked_mul(offset_size as u64);
+        let table_end = table_length.and_then(|length| length.checked_add(table_offset));
+        match table_end {
+            Some(end) if end <= trailer_offset as u64 => (),
+            _ => return Err(error("offset table does not fit the file", trailer_offset)),
+        }
+        let offsets = (0..object_count as usize)
+            .map(|index| {
+                read_uint(
+                    data,
+                    table_offset as usize + index * offset_size,
+                    offset_size,
+                )
+            })
+            .map(|offset| offset as usize)
+            .collect();
+
+        Ok(Decoder {
+            data,
+            ref_size,
+            offsets,
+            top_object,
+            tokens: Vec::new(),
+            max_tokens: data.len().saturating_mul(TOKENS_PER_BYTE),
+            decoded_bytes: 0,
+            max_decoded_bytes: data.len().saturating_mul(DECODED_BYTES_PER_BYTE),
+        })
+    }
+
+    /// Appends the tokens of object `index`, recursing into dicts and arrays.
+    fn object(&mut self, index: u64, depth: usize) -> Result<(), ParseError> {
+        let offset = self.offset_of(index)?;
+        if depth > MAX_DEPTH {
+            return Err(error("containers nested too deeply", offset));
+        }
+        if self.tokens.len() > self.max_tokens {
+            return Err(error("shared objects expand past the file size", offset));
+        }
+        let marker = self.data[offset];
+        match marker >> 4 {
+            0xA => {
+                let (count, start) = self.length(offset)?;
+                let refs = self.refs(start, count)?;
+                self.tokens.push((offset, Token::Element(Element::Array)));
+                for item in refs {
+                    self.object(item, depth + 1)?;
+                }
+                self.tokens.push((offset, Token::End));
+            }
+            0xD => {
+                let (count, start) = self.length(offset)?;
+                let refs = self.refs(start, count.saturating_mul(2))?;
+                let (keys, values) = refs.split_at(count);
+                self.tokens.push((offset, Token::Element(Element::Dict)));
+                for (&key, &value) in keys.iter().zip(values) {
+                    let key_offset = self.offset_of(key)?;
+                    let key = match self.scalar(key_offset)? {
+                        Token::Element(Element::String(key)) => key.unwrap_or_default(),
+                        _ => return Err(error("dict key is not a string", key_offset)),
+                    };
+                    self.tokens
+                        .push((key_offset, Token::Element(Element::Key(key))));
+                    self.object(value, depth + 1)?;
+                }
+                self.tokens.push((offset, Token::End));
+            }
+            _ => {
+                let token = self.scalar(offset)?;
+                self.tokens.push((offset, token));
+            }
+        }
+        Ok(())
+    }
+
+    fn scalar(&mut self, offset: usize) -> Result<Token, ParseError> {
+        let marker = self.data[offset];
+        let element = match (marker >> 4, marker & 0x0F) {
+            (0x0, 0x8) => Element::Boolean(false),
+            (0x0, 0x9) => Element::Boolean(true),
+            (0x0, _) => return Ok(Token::Unsupported("null")),
+            (0x1, size) => Element::Integer(self.integer(offset + 1, size)?),
+            (0x2, _) => return Ok(Token::Unsupported("real")),
+            (0x3, 0x3) => {
+                let bytes = self.bytes(offset + 1, 8)?;
+                let seconds = f64::from_be_bytes(bytes.try_into().unwrap());
+                match from_plist_seconds(seconds) {
+                    Some(date) => Element::Date(date),
+                    None => return Err(error("date out of range", offset)),
+                }
+            }
+            (0x4, _) => {
+                let (length, start) = self.length(offset)?;
+                self.decode(length, offset)?;
+                Element::Data(self.bytes(start, length)?.to_vec())
+            }
+            (0x5, _) => {
+                let (length, start) = self.length(offset)?;
+                self.decode(length, offset)?;
+                let text = self
+                    .bytes(start, length)?
+                    .iter()
+                    .map(|&b| b as char)
+                    .collect();
+                string(text)
+            }
+            (0x6, _) => {
+                let (length, start) = self.length(offset)?;
+                self.decode(length.saturating_mul(2), offset)?;
+                let units: Vec<u16> = self
+                    .bytes(start, length.saturating_mul(2))?
+                    .chunks(2)
+                    .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                    .collect();
+                match String::from_utf16(&units) {
+                    Ok(text) => string(text),
+                    Err(_) => return Err(error("invalid UTF-16 string", offset)),
+                }
+            }
+            (0x8, _) => return Ok(Token::Unsupported("uid")),
+            (0xA, _) => return Ok(Token::Unsupported("array")),
+            (0xC, _) => return Ok(Token::Unsupported("set")),
+            (0xD, _) => return Ok(Token::Unsupported("dict")),
+            _ => {
+                return Err(error(
+                    &format!("unknown object marker {marker:#04x}"),
+                    offset,
+                ))
+            }
+        };
+        Ok(Token::Element(element))
+    }
+
+    /// Accounts for `length` more bytes copied out of the object at `offset`.
+    fn decode(&mut self, length: usize, offset: usize) -> Result<(), ParseError> {
+        self.decoded_bytes = self.decoded_bytes.saturating_add(length);
+        match self.decoded_bytes > self.max_decoded_bytes {
+            true => Err(error("shared objects expand past the file size", offset)),
+            false => Ok(()),
+        }
+    }
+
+    /// Object count and start of the contents for strings, data and containers.
+    fn length(&self, offset: usize) -> Result<(usize, usize), ParseError> {
+        let size = self.data[offset] & 0x0F;
+        if size != 0x0F {
+            return Ok((size as usize, offset + 1));
+        }
+        let marker = *self
+            .data
+            .get(offset + 1)
+            .ok_or_else(|| error("object ends unexpectedly", offset))?;
+        if marker >> 4 != 0x1 {
+            return Err(error("object length is not an integer", offset + 1));
+        }
+        let length = self.integer(offset + 2, marker & 0x0F)?;
+        let length = usize::try_from(length).map_err(|_| error("negative length", offset + 1))?;
+        Ok((length, offset + 2 + (1 << (marker & 0x0F))))
+    }
+
+    /// Integers are 1, 2 or 4 bytes unsigned, 8 bytes signed, and 16 bytes for values past
+    /// `i64`, of which only the low 8 bytes are kept.
+    fn integer(&self, offset: usize, size: u8) -> Result<i64, ParseError> {
+        if size > 4 {
+            return Err(error("integer wider than 16 bytes", offset - 1));
+        }
+        let length = 1 << size;
+        let bytes = self.bytes(offset, length)?;
+        Ok(read_uint(bytes, length.saturating_sub(8), length.min(8)) as i64)
+    }
+
+    fn refs(&self, offset: usize, count: usize) -> Result<Vec<u64>, ParseError> {
+        let length = count
+            .checked_mul(self.ref_size)
+            .ok_or_else(|| error("container too large", offset))?;
+        let bytes = self.bytes(offset, length)?;
+        Ok(bytes
+            .chunks(self.ref_size)
+            .map(|chunk| read_uint(chunk, 0, self.ref_size))
+            .collect())
+    }
+
+    fn bytes(&self, offset: usize, length: usize) -> Result<&'a [u8], ParseError> {
+        offset
+            .checked_add(length)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| error("object ends unexpectedly", offset))
+    }
+
+    fn offset_of(&self, index: u64) -> Result<usize, ParseError> {
+        match self.offsets.get(index as usize) {
+            Some(&offset) if offset < self.data.len() - TRAILER_LENGTH => Ok(offset),
+            _ => Err(error(&format!("bad object reference {index}"), 0)),
+        }
+    }
+}
+
+/// Empty strings read as `None`, like an empty `<string></string>`.
+fn string(text: String) -> Element {
+    match text.is_empty() {
+        true => Element::String(None),
+        false => Element::String(Some(text)),
+    }
+}
+
+fn read_uint(data: &[u8], offset: usize, size: usize) -> u64 {
+    data[offset..offset + size]
+        .iter()
+        .fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn error(message: &str, offset: usize) -> ParseError {
+    ParseError::Binary {
+        message: message.to_string(),
+        at: ErrorLocation::at_byte(offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = std::fs::read("tests/fixtures/single-track.bplist").unwrap();
+
+        assert!(Tokens::decode(&data).is_ok());
+        let err = Tokens::decode(&data[..data.len() - 40]).err().unwrap();
+        assert!(matches!(err, ParseError::Binary { .. }));
+        assert!(!err.is_recoverable());
+    }
+
+    #[test]
+    fn rejects_shared_containers_that_expand_exponentially() {
+        // Arrays 0-39 each reference the next one twice, array 39 an integer
+        let mut data = MAGIC.to_vec();
+        let mut offsets = Vec::new();
+        for index in 1..=40u8 {
+            offsets.push(data.len() as u8);
+            data.extend([0xA2, index, index]);
+        }
+        offsets.push(data.len() as u8);
+        data.extend([0x10, 0x01]);
+        let table_offset = data.len() as u64;
+        data.extend(&offsets);
+        data.extend([0, 0, 0, 0, 0, 0, 1, 1]);
+        data.extend((offsets.len() as u64).to_be_bytes());
+        data.extend(0u64.to_be_bytes());
+        data.extend(table_offset.to_be_bytes());
+
+        let err = Tokens::decode(&data).err().unwrap();
+        assert!(err.to_string().contains("expand past the file size"));
+    }
+
+    #[test]
+    fn rejects_shared_data_that_expands_past_the_cap() {
+        // An array referencing one 4 KB data object 255 times, far more than real files copy
+        let mut data = MAGIC.to_vec();
+        data.extend([0xAF, 0x10, 0xFF]);
+        data.extend([1; 255]);
+        let data_offset = data.len() as u16;
+        data.extend([0x4F, 0x11, 0x10, 0x00]);
+        data.extend([0; 4096]);
+        let table_offset = data.len() as u64;
+        data.extend(8u16.to_be_bytes());
+        data.extend(data_offset.to_be_bytes());
+        data.extend([0, 0, 0, 0, 0, 0, 2, 1]);
+        data.extend(2u64.to_be_bytes());
+        data.extend(0u64.to_be_bytes());
+        data.extend(table_offset.to_be_bytes());
+
+        let err = Tokens::decode(&data).err().unwrap();
+        assert!(err.to_string().contains("expand past the file size"));
+    }
+
+    #[test]
+    fn reports_progress_per_token() {
+        let data = std::fs::read("tests/fixtures/single-track.bplist").unwrap();
+        let mut tokens = Tokens::decode(&data).unwrap();
+
+        assert_eq!(tokens.bytes_read(), 0);
+        tokens.next();
+        assert!(tokens.bytes_read() < data.len() as u64 / 10);
+        while tokens.next().is_some() {}
+        assert_eq!(tokens.bytes_read(), data.len() as u64);
+    }
+}
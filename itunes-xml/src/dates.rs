@@ -0,0 +1,72 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+
+/// Parses a plist `<date>`, e.g. `2019-01-24T13:17:00Z`.
+pub(crate) fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+pub(crate) fn format_date(date: &DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn mac_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1904, 1, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .expect("Mac epoch is a valid date")
+}
+
+/// Converts seconds since 1904-01-01, as used by `Play Date` and smart playlist rules. `None`
+/// when the date is out of range.
+pub(crate) fn from_mac_seconds(seconds: i64) -> Option<NaiveDateTime> {
+    mac_epoch().checked_add_signed(Duration::try_seconds(seconds)?)
+}
+
+pub(crate) fn to_mac_seconds(date: &NaiveDateTime) -> i64 {
+    (*date - mac_epoch()).num_seconds()
+}
+
+/// Seconds between the Unix epoch and 2001-01-01, the epoch of binary plist dates.
+const PLIST_EPOCH_OFFSET: i64 = 978_307_200;
+
+/// Converts a binary plist date, seconds since 2001-01-01 UTC. XML dates only have
+/// whole seconds, so fractions are rounded off.
+pub(crate) fn from_plist_seconds(seconds: f64) -> Option<DateTime<Utc>> {
+    if !seconds.is_finite() {
+        return None;
+    }
+    let timestamp = (seconds.round() as i64).checked_add(PLIST_EPOCH_OFFSET)?;
+    DateTime::from_timestamp(timestamp, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_mac_epoch() {
+        let play_date = from_mac_seconds(3_736_073_948).unwrap();
+
+        assert_eq!(play_date.to_string(), "2022-05-22 14:19:08");
+        assert_eq!(to_mac_seconds(&play_date), 3_736_073_948);
+        assert_eq!(from_mac_seconds(i64::MAX), None);
+    }
+
+    #[test]
+    fn round_trips_plist_dates() {
+        let date = parse_date("2019-01-24T13:17:00Z").unwrap();
+
+        assert_eq!(date.timestamp(), 1_548_335_820);
+        assert_eq!(format_date(&date), "2019-01-24T13:17:00Z");
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn converts_binary_plist_dates() {
+        let date = from_plist_seconds(570_028_620.0).unwrap();
+
+        assert_eq!(format_date(&date), "2019-01-24T13:17:00Z");
+        assert_eq!(from_plist_seconds(f64::NAN), None);
+    }
+}
@@ -0,0 +1,341 @@
+//! Differences between two exports of the same library.
+//!
+//! Track IDs are renumbered on every export, so tracks are matched by `Persistent ID` and
+//! playlists by `Playlist Persistent ID`. Tracks without a persistent ID are matched by location,
+//! as on import, and playlists without one by name.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Element, Library, Playlist, Track};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct LibraryDiff {
+    pub added_tracks: Vec<Track>,
+    pub removed_tracks: Vec<Track>,
+    pub modified_tracks: Vec<TrackChange>,
+    pub playlists: Vec<PlaylistChange>,
+    /// Tracks of either library with neither a persistent ID nor a location, which can't be
+    /// matched and so are left out of the comparison.
+    pub unmatched_tracks: Vec<Track>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TrackChange {
+    /// Persistent ID, or location for a track without one.
+    pub key: String,
+    /// Name in the newer library.
+    pub name: Option<String>,
+    pub fields: Vec<FieldChange>,
+}
+
+/// A plist key whose value differs, `None` where the key is missing.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FieldChange {
+    pub key: String,
+    pub old: Option<Element>,
+    pub new: Option<Element>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Membership changes of a playlist, with tracks given by persistent ID or, for tracks without
+/// one, by location.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PlaylistChange {
+    pub persistent_id: String,
+    pub name: String,
+    pub kind: ChangeKind,
+    pub added_tracks: Vec<String>,
+    pub removed_tracks: Vec<String>,
+}
+
+impl LibraryDiff {
+    /// No changes, unmatched tracks aside.
+    pub fn is_empty(&self) -> bool {
+        self.added_tracks.is_empty()
+            && self.removed_tracks.is_empty()
+            && self.modified_tracks.is_empty()
+            && self.playlists.is_empty()
+    }
+}
+
+/// Compares `old` with `new`, results are ordered by persistent ID or location.
+pub fn diff_libraries(old: &Library, new: &Library) -> LibraryDiff {
+    let old_tracks = tracks_by_key(old);
+    let new_tracks = tracks_by_key(new);
+    let mut diff = LibraryDiff::default();
+
+    for (key, old_track) in &old_tracks {
+        match new_tracks.get(key) {
+            None => diff.removed_tracks.push((*old_track).clone()),
+            Some(new_track) => {
+                let fields = diff_fields(old_track, new_track);
+                if !fields.is_empty() {
+                    diff.modified_tracks.push(TrackChange {
+                        key: key.to_string(),
+                        name: new_track.name.clone(),
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+    diff.added_tracks = new_tracks
+        .iter()
+        .filter(|(key, _)| !old_tracks.contains_key(*key))
+        .map(|(_, track)| (*track).clone())
+        .collect();
+    diff.unmatched_tracks = [old, new]
+        .into_iter()
+        .flat_map(|library| library.tracks.values())
+        .filter(|track| track_key(track).is_none())
+        .cloned()
+        .collect();
+    diff.unmatched_tracks.sort_by_key(|track| track.id);
+
+    let old_playlists = playlists_by_key(old);
+    let new_playlists = playlists_by_key(new);
+    let keys: BTreeSet<&PlaylistKey> = old_playlists.keys().chain(new_playlists.keys()).collect();
+    for key in keys {
+        let old_playlist = old_playlists.get(key);
+        let new_playlist = new_playlists.get(key);
+        let old_items = old_playlist.map_or_else(Vec::new, |playlist| members(old, playlist));
+        let new_items = new_playlist.map_or_else(Vec::new, |playlist| members(new, playlist));
+
+        let (kind, playlist) = match (old_playlist, new_playlist) {
+            (Some(_), Some(playlist)) => (ChangeKind::Modified, playlist),
+            (None, Some(playlist)) => (ChangeKind::Added, playlist),
+            (Some(playlist), None) => (ChangeKind::Removed, playlist),
+            (None, None) => continue,
+        };
+        let added_tracks = difference(&new_items, &old_items);
+        let removed_tracks = difference(&old_items, &new_items);
+        if kind == ChangeKind::Modified && added_tracks.is_empty() && removed_tracks.is_empty() {
+            continue;
+        }
+        diff.playlists.push(PlaylistChange {
+            persistent_id: playlist.persistent_id.clone(),
+            name: playlist.name.clone(),
+            kind,
+            added_tracks,
+            removed_tracks,
+        });
+    }
+
+    diff
+}
+
+fn diff_fields(old: &Track, new: &Track) -> Vec<FieldChange> {
+    let mut old_fields: HashMap<&str, Element> = old.fields().into_iter().collect();
+    let mut new_fields: HashMap<&str, Element> = new.fields().into_iter().collect();
+    old_fields.extend(
+        old.extra
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.clone())),
+    );
+    new_fields.extend(
+        new.extra
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.clone())),
+    );
+
+    // Dedicated fields in plist order, then extra keys alphabetically
+    let extra: BTreeSet<&str> = old
+        .extra
+        .keys()
+        .chain(new.extra.keys())
+        .map(String::as_str)
+        .collect();
+    let mut keys: Vec<&str> = Vec::new();
+    for key in old
+        .fields()
+        .into_iter()
+        .chain(new.fields())
+        .map(|(key, _)| key)
+        .chain(extra)
+    {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let old = old_fields.remove(key);
+            let new = new_fields.remove(key);
+            (old != new).then(|| FieldChange {
+                key: key.to_string(),
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+/// The persistent ID, else the location, like `find_track` does on import.
+fn track_key(track: &Track) -> Option<&str> {
+    track.persistent_id.as_deref().or(track.location.as_deref())
+}
+
+fn tracks_by_key(library: &Library) -> BTreeMap<&str, &Track> {
+    library
+        .tracks
+        .values()
+        .filter_map(|track| Some((track_key(track)?, track)))
+        .collect()
+}
+
+/// A playlist's persistent ID or, when that is empty, its name and how many playlists of that
+/// name came before it.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum PlaylistKey<'a> {
+    PersistentId(&'a str),
+    Name(&'a str, usize),
+}
+
+fn playlists_by_key(library: &Library) -> BTreeMap<PlaylistKey<'_>, &Playlist> {
+    let mut playlists: Vec<&Playlist> = library.playlists.values().collect();
+    playlists.sort_by_key(|playlist| playlist.id);
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    playlists
+        .into_iter()
+        .map(|playlist| {
+            let key = match playlist.persistent_id.as_str() {
+                "" => {
+                    let count = names.entry(playlist.name.as_str()).or_default();
+                    *count += 1;
+                    PlaylistKey::Name(&playlist.name, *count)
+                }
+                persistent_id => PlaylistKey::PersistentId(persistent_id),
+            };
+            (key, playlist)
+        })
+        .collect()
+}
+
+/// Keys of the playlist's tracks, in playlist order.
+fn members(library: &Library, playlist: &Playlist) -> Vec<String> {
+    playlist
+        .items
+        .iter()
+        .filter_map(|id| Some(track_key(library.tracks.get(id)?)?.to_string()))
+        .collect()
+}
+
+fn difference(items: &[String], other: &[String]) -> Vec<String> {
+    let other: BTreeSet<&String> = other.iter().collect();
+    let mut seen = BTreeSet::new();
+    items
+        .iter()
+        .filter(|item| !other.contains(item) && seen.insert(*item))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_itunes_xml;
+
+    #[test]
+    fn reports_track_and_playlist_changes() {
+        let old = parse_itunes_xml("tests/fixtures/Playlist-_lin next party.xml").unwrap();
+        let mut new = old.clone();
+
+        let mut ids: Vec<u64> = new.tracks.keys().copied().collect();
+        ids.sort();
+        let removed = new.tracks.remove(&ids[0]).unwrap();
+        let changed = new.tracks.get_mut(&ids[1]).unwrap();
+        changed.bpm = Some(128);
+        changed.extra.insert(
+            "Sort Show".to_string(),
+            Element::String(Some("Show".to_string())),
+        );
+        let changed_id = changed.persistent_id.clone().unwrap();
+        new.tracks.insert(
+            1,
+            Track {
+                id: 1,
+                name: Some("New".to_string()),
+                persistent_id: Some("0000000000000001".to_string()),
+                ..Default::default()
+            },
+        );
+        let playlist = new.playlists.values_mut().next().unwrap();
+        playlist.items.push(1);
+
+        let diff = diff_libraries(&old, &new);
+
+        assert_eq!(diff.removed_tracks, vec![removed.clone()]);
+        assert_eq!(diff.added_tracks.len(), 1);
+        assert_eq!(diff.modified_tracks.len(), 1);
+        assert_eq!(diff.modified_tracks[0].key, changed_id);
+        let keys: Vec<&str> = diff.modified_tracks[0]
+            .fields
+            .iter()
+            .map(|field| field.key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["BPM", "Sort Show"]);
+        assert_eq!(diff.playlists.len(), 1);
+        assert_eq!(diff.playlists[0].kind, ChangeKind::Modified);
+        assert_eq!(diff.playlists[0].added_tracks, vec!["0000000000000001"]);
+        assert_eq!(
+            diff.playlists[0].removed_tracks,
+            vec![removed.persistent_id.unwrap()]
+        );
+
+        assert!(diff_libraries(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn matches_by_location_and_name_without_persistent_ids() {
+        let track = |id: u64, location: Option<&str>, bpm: i64| Track {
+            id,
+            location: location.map(str::to_string),
+            bpm: Some(bpm),
+            ..Default::default()
+        };
+        let playlist = |id: u64, name: &str, items: Vec<u64>| Playlist {
+            id,
+            name: name.to_string(),
+            persistent_id: String::new(),
+            items,
+            ..Default::default()
+        };
+        let old = Library {
+            tracks: HashMap::from([(1, track(1, Some("file:///a.mp3"), 120))]),
+            playlists: HashMap::from([
+                (10, playlist(10, "Crate", vec![1])),
+                (11, playlist(11, "Crate", vec![])),
+            ]),
+            ..Default::default()
+        };
+        let new = Library {
+            tracks: HashMap::from([
+                (5, track(5, Some("file:///a.mp3"), 124)),
+                (6, track(6, None, 90)),
+            ]),
+            playlists: HashMap::from([
+                (20, playlist(20, "Crate", vec![5])),
+                (21, playlist(21, "Crate", vec![5])),
+            ]),
+            ..Default::default()
+        };
+
+        let diff = diff_libraries(&old, &new);
+
+        assert!(diff.added_tracks.is_empty());
+        assert!(diff.removed_tracks.is_empty());
+        assert_eq!(diff.modified_tracks[0].key, "file:///a.mp3");
+        assert_eq!(diff.unmatched_tracks.len(), 1);
+        assert_eq!(diff.playlists.len(), 1);
+        assert_eq!(diff.playlists[0].added_tracks, vec!["file:///a.mp3"]);
+    }
+}
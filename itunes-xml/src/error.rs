@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// What the parser was working on when an error occurred.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum Context {
+    #[default]
+    Document,
+    Track {
+        id: Option<u64>,
+        key: Option<String>,
+    },
+    Playlist {
+        name: Option<String>,
+        key: Option<String>,
+    },
+}
+
+/// Line and column (both 1-based) in the source document, plus the parser context.
+/// Binary plists have no lines, there `line` is 0 and `column` the byte offset.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ErrorLocation {
+    pub line: u64,
+    pub column: u64,
+    pub context: Context,
+}
+
+impl ErrorLocation {
+    pub(crate) fn at_byte(offset: usize) -> Self {
+        ErrorLocation {
+            line: 0,
+            column: offset as u64,
+            context: Context::Document,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    UnexpectedElement {
+        expected: &'static str,
+        found: String,
+        at: ErrorLocation,
+    },
+    BadInteger {
+        value: String,
+        at: ErrorLocation,
+    },
+    BadReal {
+        value: String,
+        at: ErrorLocation,
+    },
+    BadDate {
+        value: String,
+        at: ErrorLocation,
+    },
+    BadData {
+        message: String,
+        at: ErrorLocation,
+    },
+    MissingValue {
+        key: String,
+        at: ErrorLocation,
+    },
+    Xml {
+        message: String,
+        at: ErrorLocation,
+    },
+    Binary {
+        message: String,
+        at: ErrorLocation,
+    },
+    Io(io::Error),
+}
+
+impl ParseError {
+    pub fn location(&self) -> Option<&ErrorLocation> {
+        match self {
+            ParseError::UnexpectedElement { at, .. }
+            | ParseError::BadInteger { at, .. }
+            | ParseError::BadReal { at, .. }
+            | ParseError::BadDate { at, .. }
+            | ParseError::BadData { at, .. }
+            | ParseError::MissingValue { at, .. }
+            | ParseError::Xml { at, .. }
+            | ParseError::Binary { at, .. } => Some(at),
+            ParseError::Io(_) => None,
+        }
+    }
+
+    /// Whether parsing can carry on after the offending track or playlist is skipped.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            ParseError::Xml { .. } | ParseError::Binary { .. } | ParseError::Io(_)
+        )
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, item, key) = match self {
+            Context::Document => return Ok(()),
+            Context::Track { id, key } => ("track", id.map(|id| id.to_string()), key),
+            Context::Playlist { name, key } => {
+                ("playlist", name.as_ref().map(|n| format!("{n:?}")), key)
+            }
+        };
+        write!(f, " in {kind}")?;
+        if let Some(item) = item {
+            write!(f, " {item}")?;
+        }
+        if let Some(key) = key {
+            write!(f, ", key {key:?}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ErrorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "byte {}{}", self.column, self.context),
+            line => write!(f, "line {}, column {}{}", line, self.column, self.context),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedElement {
+                expected,
+                found,
+                at,
+            } => {
+                write!(f, "Expected {expected}, found {found} at {at}")
+            }
+            ParseError::BadInteger { value, at } => write!(f, "Bad integer {value:?} at {at}"),
+            ParseError::BadReal { value, at } => write!(f, "Bad real {value:?} at {at}"),
+            ParseError::BadDate { value, at } => write!(f, "Bad date {value:?} at {at}"),
+            ParseError::BadData { message, at } => write!(f, "Bad data ({message}) at {at}"),
+            ParseError::MissingValue { key, at } => write!(f, "Missing value for {key:?} at {at}"),
+            ParseError::Xml { message, at } => write!(f, "XML syntax error: {message} at {at}"),
+            ParseError::Binary { message, at } => write!(f, "Binary plist error: {message} at {at}"),
+            ParseError::Io(err) => write!(f, "Failed to read library: {err}"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
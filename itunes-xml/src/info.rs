@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{Element, Library};
+
+/// The header of an iTunes library export.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct LibraryInfo {
+    pub major_version: Option<i64>, // <key>Major Version</key><integer>1</integer>
+    pub minor_version: Option<i64>, // <key>Minor Version</key><integer>1</integer>
+    pub date: Option<DateTime<Utc>>, // <key>Date</key><date>2022-08-07T15:44:40Z</date>
+    pub application_version: Option<String>, // <key>Application Version</key><string>12.9.5.5</string>
+    pub features: Option<i64>,               // <key>Features</key><integer>5</integer>
+    pub show_content_ratings: Option<bool>,  // <key>Show Content Ratings</key><true/>
+    pub music_folder: Option<String>, // <key>Music Folder</key><string>file:///Users/qu/Music/iTunes/iTunes%20Media/</string>
+    pub library_persistent_id: Option<String>, // <key>Library Persistent ID</key><string>099BECFAF50E3D48</string>
+}
+
+impl LibraryInfo {
+    pub fn from_metadata(metadata: &HashMap<String, Element>) -> Self {
+        let integer = |key: &str| match metadata.get(key) {
+            Some(Element::Integer(i)) => Some(*i),
+            _ => None,
+        };
+        let string = |key: &str| match metadata.get(key) {
+            Some(Element::String(s)) => s.clone(),
+            _ => None,
+        };
+
+        LibraryInfo {
+            major_version: integer("Major Version"),
+            minor_version: integer("Minor Version"),
+            date: match metadata.get("Date") {
+                Some(Element::Date(date)) => Some(*date),
+                _ => None,
+            },
+            application_version: string("Application Version"),
+            features: integer("Features"),
+            show_content_ratings: match metadata.get("Show Content Ratings") {
+                Some(Element::Boolean(b)) => Some(*b),
+                _ => None,
+            },
+            music_folder: string("Music Folder"),
+            library_persistent_id: string("Library Persistent ID"),
+        }
+    }
+}
+
+impl Library {
+    pub fn info(&self) -> LibraryInfo {
+        LibraryInfo::from_metadata(&self.metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_itunes_xml;
+
+    #[test]
+    fn reads_header() {
+        let library = parse_itunes_xml("tests/fixtures/single-track.xml").unwrap();
+
+        let info = library.info();
+
+        assert_eq!(info.major_version, Some(1));
+        assert_eq!(info.application_version.as_deref(), Some("1.0.3.1"));
+        assert_eq!(info.show_content_ratings, Some(true));
+        assert_eq!(
+            info.music_folder.as_deref(),
+            Some("file:///path/to/Music/Music/Media/")
+        );
+        assert_eq!(
+            info.library_persistent_id.as_deref(),
+            Some("2F3F8612B7B1370C")
+        );
+        assert_eq!(
+            info.date.map(|date| date.to_rfc3339()),
+            Some("2020-02-24T06:20:25+00:00".to_string())
+        );
+    }
+}
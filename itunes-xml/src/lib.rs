@@ -1,19 +1,64 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::error::Error;
-use std::io::BufReader;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
 use std::{fmt::Debug, fs::File};
 
+use xml::common::Position;
 use xml::reader::{EventReader, XmlEvent};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub use diff::{diff_libraries, ChangeKind, FieldChange, LibraryDiff, PlaylistChange, TrackChange};
+pub use error::{Context, ErrorLocation, ParseError};
+pub use info::LibraryInfo;
+pub use locations::{remap_location, LocationRule};
+pub use playlist_files::{
+    read_playlist_file, write_playlist_file, PlaylistEntry, PlaylistFormat,
+};
+pub use rekordbox::{
+    is_rekordbox_xml, parse_rekordbox_xml, parse_rekordbox_xml_reader, visit_rekordbox_xml,
+    visit_rekordbox_xml_reader, CuePoint, TempoMarker,
+};
+pub use rekordbox_writer::{to_rekordbox_xml_string, write_rekordbox_xml};
+pub use report::{Diagnostic, ParseReport};
+pub use smart::{
+    Conjunction, Criteria, Field, Limit, LimitUnit, Operator, Rule, RuleValue, Selection,
+    SmartPlaylist, SmartPlaylistError,
+};
+pub use traktor::{
+    is_traktor_nml, parse_traktor_nml, parse_traktor_nml_reader, visit_traktor_nml,
+    visit_traktor_nml_reader,
+};
+pub use tree::{playlist_tree, PlaylistNode};
+pub use visitor::{LibraryVisitor, Progress};
+pub use writer::{to_itunes_xml_string, write_itunes_xml};
+
+mod attributes;
+mod bplist;
+mod dates;
+mod diff;
+mod error;
+mod info;
+mod locations;
+mod playlist_files;
+mod rekordbox;
+mod rekordbox_writer;
+mod report;
+mod smart;
+mod traktor;
+mod tree;
+mod visitor;
+mod writer;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
 pub struct Library {
     pub metadata: HashMap<String, Element>,
     pub tracks: HashMap<u64, Track>,
     pub playlists: HashMap<u64, Playlist>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum Element {
     Plist,
     Dict,
@@ -22,8 +67,8 @@ pub enum Element {
     Boolean(bool),
     Integer(i64),
     String(Option<String>),
-    Data(Option<String>),
-    Date(String),
+    Data(Vec<u8>),
+    Date(DateTime<Utc>),
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
@@ -55,17 +100,17 @@ pub struct Track {
     pub track_count: Option<i64>,            // `bson:"TrackCount,omitempty"`
     pub year: Option<i64>,                   // `bson:"Year,omitempty"`
     pub bpm: Option<i64>,                    // `bson:"BPM,omitempty"`
-    pub date_modified: Option<String>,       // `bson:"DateModified,omitempty"`
-    pub date_added: Option<String>,          // `bson:"DateAdded,omitempty"`
+    pub date_modified: Option<DateTime<Utc>>, // `bson:"DateModified,omitempty"`
+    pub date_added: Option<DateTime<Utc>>,   // `bson:"DateAdded,omitempty"`
     pub bit_rate: Option<i64>,               // `bson:"BitRate,omitempty"`
     pub sample_rate: Option<i64>,            // `bson:"SampleRate,omitempty"`
     pub equalizer: Option<String>,           // `bson:"Equalizer,omitempty"`
     pub play_count: Option<i64>,             // `bson:"PlayCount,omitempty"`
-    pub play_date: Option<i64>,              // `bson:"PlayDate,omitempty"`
-    pub play_date_utc: Option<String>,       // `bson:"PlayDateUTC,omitempty"`
+    pub play_date: Option<NaiveDateTime>,    // `bson:"PlayDate,omitempty"`, local time of the exporting machine
+    pub play_date_utc: Option<DateTime<Utc>>, // `bson:"PlayDateUTC,omitempty"`
     pub skip_count: Option<i64>,             // `bson:"SkipCount,omitempty"`
-    pub skip_date: Option<String>,           // `bson:"SkipDate,omitempty"`
-    pub release_date: Option<String>,        // `bson:"ReleaseDate,omitempty"`
+    pub skip_date: Option<DateTime<Utc>>,    // `bson:"SkipDate,omitempty"`
+    pub release_date: Option<DateTime<Utc>>, // `bson:"ReleaseDate,omitempty"`
     pub normalization: Option<i64>,          // `bson:"Normalization,omitempty"`
     pub rating: Option<i64>,                 // `bson:"Rating,omitempty"`
     pub rating_computed: Option<bool>,       // `bson:"RatingComputed,omitempty"`
@@ -88,9 +133,21 @@ pub struct Track {
     pub file_folder_count: Option<i64>,      // `bson:"FileFolderCount,omitempty"`
     pub library_folder_count: Option<i64>,   // `bson:"LibraryFolderCount,omitempty"`
     pub volume_adjustment: Option<i64>,      // `bson:"VolumeAdjustment,omitempty"`
+    pub grouping: Option<String>,            // `bson:"Grouping,omitempty"`
+    pub work: Option<String>,                // `bson:"Work,omitempty"`
+    pub movement_name: Option<String>,       // `bson:"MovementName,omitempty"`
+    pub movement_number: Option<i64>,        // `bson:"MovementNumber,omitempty"`
+    pub start_time: Option<i64>,             // `bson:"StartTime,omitempty"`, milliseconds
+    pub stop_time: Option<i64>,              // `bson:"StopTime,omitempty"`, milliseconds
+    #[serde(default)]
+    pub extra: HashMap<String, Element>,     // Fields without a dedicated member, by plist key
+    #[serde(default)]
+    pub tempo_markers: Vec<TempoMarker>,     // Rekordbox beat grid, not part of iTunes exports
+    #[serde(default)]
+    pub cue_points: Vec<CuePoint>,           // Rekordbox cues and loops, not part of iTunes exports
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
 pub struct Playlist {
     pub id: u64,                     // <key>Playlist ID</key><integer>50344</integer>
     pub name: String, // `bson:"Name,omitempty"` // <key>Name</key><string>Music</string>
@@ -107,8 +164,8 @@ pub struct Playlist {
     pub tv_shows: Option<bool>, // TV Shows
     pub audiobooks: Option<bool>, // Audiobooks
     pub podcasts: Option<bool>, // Podcasts
-    pub items: HashSet<u64>, // `bson:"Items,omitempty"` // <key>Playlist Items</key>
-    pub smart_info: Option<String>, /*
+    pub items: Vec<u64>, // `bson:"Items,omitempty"` // <key>Playlist Items</key>, in playlist order
+    pub smart_info: Option<Vec<u8>>, /*
                          <key>Smart Info</key>
                          <data>
                          AQEAAwAAAAIAAAAZAAAAAAAAAAcAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
@@ -116,7 +173,7 @@ pub struct Playlist {
                          AAAAAA==
                          </data>
                          */
-    pub smart_criteria: Option<String>, /*
+    pub smart_criteria: Option<Vec<u8>>, /*
                                            <key>Smart Criteria</key>
                                            <data>
                                            U0xzdAABAAEAAAADAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
@@ -133,313 +190,642 @@ pub struct Playlist {
                                         */
 }
 
-pub fn parse_itunes_xml(file_path: &str) -> Result<Library, Box<dyn Error>> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-    let parser = EventReader::new(reader);
-    let elements_iterator = ElementsIterator { parser };
+pub fn parse_itunes_xml(file_path: &str) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    visit_itunes_xml(file_path, false, &mut library)?;
+    Ok(library)
+}
+
+/// Like [`parse_itunes_xml`], but skips malformed tracks and playlists instead of failing,
+/// describing everything that was skipped or ignored in the returned [`ParseReport`].
+pub fn parse_itunes_xml_lenient(file_path: &str) -> Result<(Library, ParseReport), ParseError> {
+    let mut library = Library::default();
+    let report = visit_itunes_xml(file_path, true, &mut library)?;
+    Ok((library, report))
+}
 
-    let library = parse_document(elements_iterator)?;
+pub fn parse_itunes_xml_reader<R: Read>(reader: R) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    visit_itunes_xml_reader(reader, false, &mut library)?;
     Ok(library)
 }
 
-fn parse_document(mut it: ElementsIterator) -> Result<Library, String> {
-    let mut tracks = HashMap::<u64, Track>::new();
-    let mut playlists = HashMap::<u64, Playlist>::new();
-    let mut metadata = HashMap::<String, Element>::new();
+pub fn parse_itunes_xml_str(xml: &str) -> Result<Library, ParseError> {
+    parse_itunes_xml_bytes(xml.as_bytes())
+}
 
-    match it.next() {
+pub fn parse_itunes_xml_bytes(bytes: &[u8]) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    let mut elements_iterator = ElementsIterator::new(bytes, Some(bytes.len() as u64), false)?;
+    parse_document(&mut elements_iterator, &mut library)?;
+    Ok(library)
+}
+
+/// Streams tracks and playlists to `visitor` as they are parsed, reporting byte-offset progress.
+pub fn visit_itunes_xml<V: LibraryVisitor>(
+    file_path: &str,
+    lenient: bool,
+    visitor: &mut V,
+) -> Result<ParseReport, ParseError> {
+    let file = File::open(file_path)?;
+    let total_bytes = file.metadata().ok().map(|metadata| metadata.len());
+    let mut elements_iterator = ElementsIterator::new(file, total_bytes, lenient)?;
+
+    parse_document(&mut elements_iterator, visitor)?;
+    Ok(elements_iterator.report)
+}
+
+/// Like [`visit_itunes_xml`] for any reader. The total size is unknown, so
+/// [`Progress::percent`] is always `None`.
+pub fn visit_itunes_xml_reader<R: Read, V: LibraryVisitor>(
+    reader: R,
+    lenient: bool,
+    visitor: &mut V,
+) -> Result<ParseReport, ParseError> {
+    let mut elements_iterator = ElementsIterator::new(reader, None, lenient)?;
+
+    parse_document(&mut elements_iterator, visitor)?;
+    Ok(elements_iterator.report)
+}
+
+fn parse_document<R: Read, V: LibraryVisitor>(
+    it: &mut ElementsIterator<R>,
+    visitor: &mut V,
+) -> Result<(), ParseError> {
+    match it.next_element()? {
         Some(Element::Plist) => {
             // println!("Skip plist wrapper start");
         }
-        element => return Err(format!("Unexpected element {:?}", element)),
+        element => return Err(it.unexpected("<plist>", element)),
     };
 
-    match it.next() {
+    match it.next_element()? {
         Some(Element::Dict) => {
             // println!("Skip root dict start")
         }
-        element => return Err(format!("Unexpected element {:?}", element)),
+        element => return Err(it.unexpected("<dict>", element)),
     };
 
     loop {
-        let current_key = match it.next() {
+        let current_key = match it.next_element()? {
             Some(Element::Key(k)) => k,
-            Some(element) => return Err(format!("Unexpected element {:?}", element)),
             None => break,
+            element => return Err(it.unexpected("<key>", element)),
         };
-        let current_value = match it.next() {
+        let current_value = match it.next_element()? {
             Some(element) => element,
-            None => return Err(format!("Received key without the value: {}", current_key)),
+            None => {
+                return Err(ParseError::MissingValue {
+                    key: current_key,
+                    at: it.location(),
+                })
+            }
         };
 
         match current_key.as_str() {
             "Tracks" => {
-                while let Some(track) = it.next_track() {
-                    tracks.insert(track.id, track);
+                while let Some(track) = it.next_track()? {
+                    visitor.track(track);
+                    visitor.progress(it.progress());
                 }
+                it.context = Context::Document;
             }
             "Playlists" => {
-                while let Some(playlist) = it.next_playlist() {
-                    playlists.insert(playlist.id, playlist);
+                while let Some(playlist) = it.next_playlist()? {
+                    visitor.playlist(playlist);
+                    visitor.progress(it.progress());
                 }
+                it.context = Context::Document;
             }
             _ => {
-                metadata.insert(current_key, current_value);
+                visitor.metadata(current_key, current_value);
             }
         }
     }
 
-    Ok(Library {
-        metadata,
-        tracks,
-        playlists,
-    })
+    Ok(())
 }
 
-struct ElementsIterator {
-    parser: EventReader<BufReader<File>>,
+/// Counts the bytes handed to the XML parser, for progress reporting.
+pub(crate) struct CountingReader<R> {
+    pub(crate) inner: R,
+    pub(crate) bytes_read: u64,
 }
 
-impl ElementsIterator {
-    fn next_track(&mut self) -> Option<Track> {
-        let mut track = Track::default();
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read += read as u64;
+        Ok(read)
+    }
+}
 
-        let track_id_str = match self.next() {
-            Some(Element::Key(k)) => k,
-            None => return None,
-            element => panic!("Unexpected element {:?}", element),
-        };
-        match self.next() {
-            Some(Element::Dict) => (),
-            // Some(Element::Dict) => println!("Start track: {:?}", track_id),
-            element => panic!("Unexpected element {:?}", element),
+/// Where elements come from: an XML event stream, or a binary plist decoded up front.
+enum Source<R: Read> {
+    Xml(Box<EventReader<BufReader<CountingReader<R>>>>),
+    Binary(bplist::Tokens),
+}
+
+struct ElementsIterator<R: Read> {
+    source: Source<R>,
+    total_bytes: Option<u64>,
+    context: Context,
+    /// Number of currently open plist, dict and array elements.
+    depth: usize,
+    lenient: bool,
+    report: ParseReport,
+}
+
+impl<R: Read> ElementsIterator<R> {
+    /// Reads binary plists when the input starts with `bplist00`, XML otherwise.
+    fn new(reader: R, total_bytes: Option<u64>, lenient: bool) -> Result<Self, ParseError> {
+        let mut reader = BufReader::new(CountingReader {
+            inner: reader,
+            bytes_read: 0,
+        });
+        let source = match reader.fill_buf()?.starts_with(bplist::MAGIC) {
+            true => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                Source::Binary(bplist::Tokens::decode(&data)?)
+            }
+            false => Source::Xml(Box::new(EventReader::new(reader))),
         };
+        Ok(ElementsIterator {
+            source,
+            total_bytes,
+            context: Context::Document,
+            depth: 0,
+            lenient,
+            report: ParseReport::default(),
+        })
+    }
 
-        track.id = track_id_str
-            .parse::<u64>()
-            .unwrap_or_else(|_| panic!("Failed to parse track id: {:?}", track_id_str));
+    fn next_track(&mut self) -> Result<Option<Track>, ParseError> {
         loop {
-            let field_key = match self.next() {
+            self.context = Context::Track { id: None, key: None };
+
+            let track_id_str = match self.next_element()? {
+                Some(Element::Key(k)) => k,
+                None => return Ok(None),
+                element => return Err(self.unexpected("track id <key>", element)),
+            };
+            match self.next_element()? {
+                Some(Element::Dict) => (),
+                // Some(Element::Dict) => println!("Start track: {:?}", track_id),
+                element => return Err(self.unexpected("<dict>", element)),
+            };
+
+            let level = self.depth;
+            match self.read_track(track_id_str) {
+                Ok(track) => return Ok(Some(track)),
+                Err(err) if self.lenient && err.is_recoverable() => {
+                    self.skip_to_depth(level - 1)?;
+                    self.report.skipped_tracks += 1;
+                    self.report.diagnostics.push(Diagnostic::skipped(&err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn read_track(&mut self, track_id_str: String) -> Result<Track, ParseError> {
+        let id = match track_id_str.parse::<u64>() {
+            Ok(id) => id,
+            Err(_) => {
+                return Err(ParseError::BadInteger {
+                    value: track_id_str,
+                    at: self.location(),
+                })
+            }
+        };
+        let mut track = Track {
+            id,
+            ..Default::default()
+        };
+        self.context = Context::Track {
+            id: Some(track.id),
+            key: None,
+        };
+        loop {
+            let field_key = match self.next_element()? {
                 Some(Element::Key(k)) => k,
                 None => break,
-                element => panic!("Unexpected element {:?}", element),
+                element => return Err(self.unexpected("<key>", element)),
             };
             // println!("Field: {:?}", field_key);
+            self.set_key(&field_key);
 
             match field_key.as_ref() {
                 "Track ID" => {
-                    self.next_int();
+                    self.next_int()?;
+                }
+                "Name" => track.name = self.next_str()?,
+                "Artist" => track.artist = self.next_str()?,
+                "Album Artist" => track.album_artist = self.next_str()?,
+                "Composer" => track.composer = self.next_str()?,
+                "Genre" => track.genre = self.next_str()?,
+                "Album" => track.album = self.next_str()?,
+                "Kind" => track.kind = self.next_str()?,
+                "Loved" => track.loved = self.next_bool()?,
+                "Disliked" => track.disliked = self.next_bool()?,
+                "Matched" => track.matched = self.next_bool()?,
+                "Explicit" => track.explicit = self.next_bool()?,
+                "Compilation" => track.compilation = self.next_bool()?,
+                "Part Of Gapless Album" => track.part_of_gapless_album = self.next_bool()?,
+                "Movie" => track.movie = self.next_bool()?,
+                "Podcast" => track.podcast = self.next_bool()?,
+                "Unplayed" => track.unplayed = self.next_bool()?,
+                "Comments" => track.comments = self.next_str()?,
+                "Content Rating" => track.content_rating = self.next_str()?,
+                "Size" => track.size = self.next_int()?,
+                "Total Time" => track.total_time = self.next_int()?,
+                "Disc Number" => track.disc_number = self.next_int()?,
+                "Disc Count" => track.disc_count = self.next_int()?,
+                "Track Number" => track.track_number = self.next_int()?,
+                "Track Count" => track.track_count = self.next_int()?,
+                "Year" => track.year = self.next_int()?,
+                "BPM" => track.bpm = self.next_int()?,
+                "Date Modified" => track.date_modified = self.next_date()?,
+                "Date Added" => track.date_added = self.next_date()?,
+                "Bit Rate" => track.bit_rate = self.next_int()?,
+                "Sample Rate" => track.sample_rate = self.next_int()?,
+                "Equalizer" => track.equalizer = self.next_str()?,
+                "Play Count" => track.play_count = self.next_int()?,
+                "Play Date" => track.play_date = self.next_mac_date()?,
+                "Play Date UTC" => track.play_date_utc = self.next_date()?,
+                "Skip Count" => track.skip_count = self.next_int()?,
+                "Skip Date" => track.skip_date = self.next_date()?,
+                "Release Date" => track.release_date = self.next_date()?,
+                "Normalization" => track.normalization = self.next_int()?,
+                "Rating" => track.rating = self.next_int()?,
+                "Rating Computed" => track.rating_computed = self.next_bool()?,
+                "Album Rating" => track.album_rating = self.next_int()?,
+                "Album Rating Computed" => track.album_rating_computed = self.next_bool()?,
+                "Artwork Count" => track.artwork_count = self.next_int()?,
+                "Sort Name" => track.sort_name = self.next_str()?,
+                "Sort Album" => track.sort_album = self.next_str()?,
+                "Sort Album Artist" => track.sort_album_artist = self.next_str()?,
+                "Sort Composer" => track.sort_composer = self.next_str()?,
+                "Sort Artist" => track.sort_artist = self.next_str()?,
+                "Persistent ID" => track.persistent_id = self.next_str()?,
+                "Track Type" => track.track_type = self.next_str()?,
+                "Purchased" => track.purchased = self.next_bool()?,
+                "Music Video" => track.music_video = self.next_bool()?,
+                "Has Video" => track.has_video = self.next_bool()?,
+                "HD" => track.hd = self.next_bool()?,
+                "Favorited" => track.favorited = self.next_bool()?,
+                "Location" => track.location = self.next_str()?,
+                "File Folder Count" => track.file_folder_count = self.next_int()?,
+                "Library Folder Count" => track.library_folder_count = self.next_int()?,
+                "Volume Adjustment" => track.volume_adjustment = self.next_int()?,
+                "Grouping" => track.grouping = self.next_str()?,
+                "Work" => track.work = self.next_str()?,
+                "Movement Name" => track.movement_name = self.next_str()?,
+                "Movement Number" => track.movement_number = self.next_int()?,
+                "Start Time" => track.start_time = self.next_int()?,
+                "Stop Time" => track.stop_time = self.next_int()?,
+                _ => {
+                    let level = self.depth;
+                    match self.next_value()? {
+                        Element::Dict | Element::Array => {
+                            self.warn(format!("Skipped nested value of field: {:?}", field_key));
+                            self.skip_to_depth(level)?;
+                        }
+                        value => {
+                            track.extra.insert(field_key, value);
+                        }
+                    }
                 }
-                "Name" => track.name = self.next_str(),
-                "Artist" => track.artist = self.next_str(),
-                "Album Artist" => track.album_artist = self.next_str(),
-                "Composer" => track.composer = self.next_str(),
-                "Genre" => track.genre = self.next_str(),
-                "Album" => track.album = self.next_str(),
-                "Kind" => track.kind = self.next_str(),
-                "Loved" => track.loved = self.next_bool(),
-                "Disliked" => track.disliked = self.next_bool(),
-                "Matched" => track.matched = self.next_bool(),
-                "Explicit" => track.explicit = self.next_bool(),
-                "Compilation" => track.compilation = self.next_bool(),
-                "Part Of Gapless Album" => track.part_of_gapless_album = self.next_bool(),
-                "Movie" => track.movie = self.next_bool(),
-                "Podcast" => track.podcast = self.next_bool(),
-                "Unplayed" => track.unplayed = self.next_bool(),
-                "Comments" => track.comments = self.next_str(),
-                "Content Rating" => track.content_rating = self.next_str(),
-                "Size" => track.size = self.next_int(),
-                "Total Time" => track.total_time = self.next_int(),
-                "Disc Number" => track.disc_number = self.next_int(),
-                "Disc Count" => track.disc_count = self.next_int(),
-                "Track Number" => track.track_number = self.next_int(),
-                "Track Count" => track.track_count = self.next_int(),
-                "Year" => track.year = self.next_int(),
-                "BPM" => track.bpm = self.next_int(),
-                "Date Modified" => track.date_modified = self.next_date(),
-                "Date Added" => track.date_added = self.next_date(),
-                "Bit Rate" => track.bit_rate = self.next_int(),
-                "Sample Rate" => track.sample_rate = self.next_int(),
-                "Equalizer" => track.equalizer = self.next_str(),
-                "Play Count" => track.play_count = self.next_int(),
-                "Play Date" => track.play_date = self.next_int(),
-                "Play Date UTC" => track.play_date_utc = self.next_date(),
-                "Skip Count" => track.skip_count = self.next_int(),
-                "Skip Date" => track.skip_date = self.next_date(),
-                "Release Date" => track.release_date = self.next_date(),
-                "Normalization" => track.normalization = self.next_int(),
-                "Rating" => track.rating = self.next_int(),
-                "Rating Computed" => track.rating_computed = self.next_bool(),
-                "Album Rating" => track.album_rating = self.next_int(),
-                "Album Rating Computed" => track.album_rating_computed = self.next_bool(),
-                "Artwork Count" => track.artwork_count = self.next_int(),
-                "Sort Name" => track.sort_name = self.next_str(),
-                "Sort Album" => track.sort_album = self.next_str(),
-                "Sort Album Artist" => track.sort_album_artist = self.next_str(),
-                "Sort Composer" => track.sort_composer = self.next_str(),
-                "Sort Artist" => track.sort_artist = self.next_str(),
-                "Persistent ID" => track.persistent_id = self.next_str(),
-                "Track Type" => track.track_type = self.next_str(),
-                "Purchased" => track.purchased = self.next_bool(),
-                "Music Video" => track.music_video = self.next_bool(),
-                "Has Video" => track.has_video = self.next_bool(),
-                "HD" => track.hd = self.next_bool(),
-                "Favorited" => track.favorited = self.next_bool(),
-                "Location" => track.location = self.next_str(),
-                "File Folder Count" => track.file_folder_count = self.next_int(),
-                "Library Folder Count" => track.library_folder_count = self.next_int(),
-                "Volume Adjustment" => track.volume_adjustment = self.next_int(),
-                field => {eprintln!("Unknown field: {:?}, {:?}", field, self.next());},
             }
         }
 
-        Some(track)
+        Ok(track)
     }
 
-    fn next_playlist(&mut self) -> Option<Playlist> {
-        match self.next() {
-            Some(Element::Dict) => (),
-            // Some(Element::Dict) => println!("Start playlist"),
-            None => return None,
-            element => panic!("Unexpected element {:?}", element),
-        };
+    fn next_playlist(&mut self) -> Result<Option<Playlist>, ParseError> {
+        loop {
+            self.context = Context::Playlist { name: None, key: None };
+            match self.next_element()? {
+                Some(Element::Dict) => (),
+                // Some(Element::Dict) => println!("Start playlist"),
+                None => return Ok(None),
+                element => return Err(self.unexpected("<dict>", element)),
+            };
 
+            let level = self.depth;
+            match self.read_playlist() {
+                Ok(playlist) => return Ok(Some(playlist)),
+                Err(err) if self.lenient && err.is_recoverable() => {
+                    self.skip_to_depth(level - 1)?;
+                    self.report.skipped_playlists += 1;
+                    self.report.diagnostics.push(Diagnostic::skipped(&err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn read_playlist(&mut self) -> Result<Playlist, ParseError> {
         let mut playlist = Playlist::default();
 
         loop {
-            let field_key = match self.next() {
+            let field_key = match self.next_element()? {
                 Some(Element::Key(k)) => k,
                 None => break,
-                element => panic!("Unexpected element {:?}", element),
+                element => return Err(self.unexpected("<key>", element)),
             };
+            self.set_key(&field_key);
 
             match field_key.as_str() {
-                "Playlist ID" => playlist.id = self.next_int().unwrap() as u64,
-                "Name" => playlist.name = self.next_str().unwrap(),
-                "Playlist Persistent ID" => playlist.persistent_id = self.next_str().unwrap(),
-                "Description" => playlist.description = self.next_str(),
-                "Parent Persistent ID" => playlist.parent_persistent_id = self.next_str(),
-                "All Items" => playlist.all_items = self.next_bool().unwrap(),
-                "Distinguished Kind" => playlist.distinguished_kind = self.next_int(),
-                "Music" => playlist.music = self.next_bool(),
-                "Master" => playlist.master = self.next_bool(),
-                "Visible" => playlist.visible = self.next_bool(),
-                "Folder" => playlist.folder = self.next_bool(),
-                "Movies" => playlist.movies = self.next_bool(),
-                "TV Shows" => playlist.tv_shows = self.next_bool(),
-                "Audiobooks" => playlist.audiobooks = self.next_bool(),
-                "Podcasts" => playlist.podcasts = self.next_bool(),
-                "Smart Info" => playlist.smart_info = self.next_str(),
-                "Smart Criteria" => playlist.smart_criteria = self.next_str(),
+                "Playlist ID" => playlist.id = self.next_int()?.unwrap_or_default() as u64,
+                "Name" => {
+                    playlist.name = self.next_str()?.unwrap_or_default();
+                    self.context = Context::Playlist {
+                        name: Some(playlist.name.clone()),
+                        key: Some(field_key),
+                    };
+                }
+                "Playlist Persistent ID" => {
+                    playlist.persistent_id = self.next_str()?.unwrap_or_default()
+                }
+                "Description" => playlist.description = self.next_str()?,
+                "Parent Persistent ID" => playlist.parent_persistent_id = self.next_str()?,
+                "All Items" => playlist.all_items = self.next_bool()?.unwrap_or_default(),
+                "Distinguished Kind" => playlist.distinguished_kind = self.next_int()?,
+                "Music" => playlist.music = self.next_bool()?,
+                "Master" => playlist.master = self.next_bool()?,
+                "Visible" => playlist.visible = self.next_bool()?,
+                "Folder" => playlist.folder = self.next_bool()?,
+                "Movies" => playlist.movies = self.next_bool()?,
+                "TV Shows" => playlist.tv_shows = self.next_bool()?,
+                "Audiobooks" => playlist.audiobooks = self.next_bool()?,
+                "Podcasts" => playlist.podcasts = self.next_bool()?,
+                "Smart Info" => playlist.smart_info = self.next_data()?,
+                "Smart Criteria" => playlist.smart_criteria = self.next_data()?,
                 "Playlist Items" => {
-                    match self.next() {
+                    match self.next_element()? {
                         Some(Element::Array) => (),
-                        element => panic!("Unexpected element {:?}", element),
+                        element => return Err(self.unexpected("<array>", element)),
                     }
 
                     loop {
-                        match self.next() {
+                        match self.next_element()? {
                             Some(Element::Dict) => {
                                 // println!("Start playlist item")
                             }
                             None => break,
-                            element => panic!("Unexpected element {:?}", element),
+                            element => return Err(self.unexpected("<dict>", element)),
                         };
-                        match self.next() {
+                        match self.next_element()? {
                             Some(Element::Key(k)) if k == "Track ID" => {
                                 // println!("Playlist item key")
                             }
-                            element => panic!("Unexpected element {:?}", element),
-                        };
-                        match self.next_int() {
-                            Some(id) => playlist.items.insert(id as u64),
-                            // Some(Element::Integer(i)) => println!("Playlist item: {:?}", i),
-                            element => panic!("Unexpected element {:?}", element),
+                            element => return Err(self.unexpected("\"Track ID\" <key>", element)),
                         };
-                        match self.next() {
+                        if let Some(id) = self.next_int()? {
+                            playlist.items.push(id as u64);
+                        }
+                        match self.next_element()? {
                             None => {
                                 // println!("Stop playlist item")
                             }
-                            element => panic!("Unexpected element {:?}", element),
+                            element => return Err(self.unexpected("</dict>", element)),
                         };
                     }
                 }
-                element => panic!("Unexpected element {:?}", element),
+                _ if self.lenient => {
+                    self.warn(format!("Unknown playlist key: {:?}", field_key));
+                    self.skip_value()?;
+                }
+                _ => return Err(self.unexpected("playlist <key>", Some(Element::Key(field_key)))),
             }
         }
 
-        Some(playlist)
+        Ok(playlist)
     }
 
-    fn next_bool(&mut self) -> Option<bool> {
-        match self.next() {
-            Some(Element::Boolean(b)) => Some(b),
-            element => panic!("Unexpected element {:?}", element),
+    fn next_value(&mut self) -> Result<Element, ParseError> {
+        match self.next_element()? {
+            Some(element) => Ok(element),
+            None => Err(ParseError::MissingValue {
+                key: self.current_key(),
+                at: self.location(),
+            }),
         }
     }
 
-    fn next_int(&mut self) -> Option<i64> {
-        match self.next() {
-            Some(Element::Integer(i)) => Some(i),
-            element => panic!("Unexpected element {:?}", element),
+    fn next_bool(&mut self) -> Result<Option<bool>, ParseError> {
+        match self.next_value()? {
+            Element::Boolean(b) => Ok(Some(b)),
+            element => Err(self.unexpected("<true/> or <false/>", Some(element))),
         }
     }
 
-    fn next_str(&mut self) -> Option<String> {
-        match self.next() {
-            Some(Element::String(s)) => s,
-            element => panic!("Unexpected element {:?}", element),
+    fn next_int(&mut self) -> Result<Option<i64>, ParseError> {
+        match self.next_value()? {
+            Element::Integer(i) => Ok(Some(i)),
+            element => Err(self.unexpected("<integer>", Some(element))),
         }
     }
 
-    fn next_date(&mut self) -> Option<String> {
-        match self.next() {
-            Some(Element::Date(d)) => Some(d),
-            element => panic!("Unexpected element {:?}", element),
+    fn next_str(&mut self) -> Result<Option<String>, ParseError> {
+        match self.next_value()? {
+            Element::String(s) => Ok(s),
+            element => Err(self.unexpected("<string>", Some(element))),
         }
     }
-}
 
-impl Iterator for ElementsIterator {
-    type Item = Element;
+    fn next_data(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        match self.next_value()? {
+            Element::Data(d) => Ok(Some(d)),
+            element => Err(self.unexpected("<data>", Some(element))),
+        }
+    }
+
+    fn next_date(&mut self) -> Result<Option<DateTime<Utc>>, ParseError> {
+        match self.next_value()? {
+            Element::Date(d) => Ok(Some(d)),
+            element => Err(self.unexpected("<date>", Some(element))),
+        }
+    }
+
+    /// An integer of seconds since 1904, as `Play Date` is written.
+    fn next_mac_date(&mut self) -> Result<Option<NaiveDateTime>, ParseError> {
+        let Some(seconds) = self.next_int()? else {
+            return Ok(None);
+        };
+        match dates::from_mac_seconds(seconds) {
+            Some(date) => Ok(Some(date)),
+            None => Err(ParseError::BadDate {
+                value: seconds.to_string(),
+                at: self.location(),
+            }),
+        }
+    }
+
+    /// Consumes the next value, including the contents of a nested dict or array.
+    fn skip_value(&mut self) -> Result<(), ParseError> {
+        let level = self.depth;
+        self.next_value()?;
+        self.skip_to_depth(level)
+    }
+
+    /// Consumes elements until the container nesting is back down to `level`.
+    fn skip_to_depth(&mut self, level: usize) -> Result<(), ParseError> {
+        while self.depth > level {
+            let depth = self.depth;
+            if self.next_element()?.is_none() && self.depth == depth {
+                // End of document
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a non-fatal problem in the report, in strict mode as well.
+    fn warn(&mut self, reason: String) {
+        let diagnostic = Diagnostic::warning(&self.location(), reason);
+        self.report.diagnostics.push(diagnostic);
+    }
+
+    fn set_key(&mut self, field_key: &str) {
+        match &mut self.context {
+            Context::Track { key, .. } | Context::Playlist { key, .. } => {
+                *key = Some(field_key.to_string())
+            }
+            Context::Document => (),
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn current_key(&self) -> String {
+        match &self.context {
+            Context::Track { key, .. } | Context::Playlist { key, .. } => {
+                key.clone().unwrap_or_default()
+            }
+            Context::Document => String::default(),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let bytes_read = match &self.source {
+            Source::Xml(parser) => parser.source().get_ref().bytes_read,
+            Source::Binary(tokens) => tokens.bytes_read(),
+        };
+        Progress {
+            bytes_read,
+            total_bytes: self.total_bytes,
+        }
+    }
+
+    fn location(&self) -> ErrorLocation {
+        match &self.source {
+            Source::Xml(parser) => {
+                let position = parser.position();
+                ErrorLocation {
+                    line: position.row + 1,
+                    column: position.column + 1,
+                    context: self.context.clone(),
+                }
+            }
+            Source::Binary(tokens) => ErrorLocation {
+                context: self.context.clone(),
+                ..ErrorLocation::at_byte(tokens.offset())
+            },
+        }
+    }
+
+    fn unexpected(&self, expected: &'static str, found: Option<Element>) -> ParseError {
+        let found = match found {
+            Some(element) => format!("{:?}", element),
+            None => "end of container".to_string(),
+        };
+        ParseError::UnexpectedElement {
+            expected,
+            found,
+            at: self.location(),
+        }
+    }
+
+    /// Returns the next element, or `None` when the enclosing dict, array or plist ends.
+    fn next_element(&mut self) -> Result<Option<Element>, ParseError> {
+        let parser = match &mut self.source {
+            Source::Xml(parser) => parser,
+            Source::Binary(tokens) => {
+                return match tokens.next() {
+                    Some(bplist::Token::Element(element)) => {
+                        if matches!(element, Element::Plist | Element::Dict | Element::Array) {
+                            self.depth += 1;
+                        }
+                        Ok(Some(element))
+                    }
+                    Some(bplist::Token::End) => {
+                        self.depth -= 1;
+                        Ok(None)
+                    }
+                    Some(bplist::Token::Unsupported(tag)) => Err(ParseError::UnexpectedElement {
+                        expected: "plist element",
+                        found: format!("<{}>", tag),
+                        at: self.location(),
+                    }),
+                    // End of document
+                    None => Ok(None),
+                };
+            }
+        };
         let mut current_tag = "";
         let mut contents: Option<String> = None;
         loop {
-            match self.parser.next() {
+            match parser.next() {
                 Ok(XmlEvent::StartElement { name, .. }) => {
+                    if matches!(name.local_name.as_str(), "plist" | "dict" | "array") {
+                        self.depth += 1;
+                    }
                     current_tag = match name.local_name.as_str() {
-                        "plist" => return Some(Element::Plist),
-                        "dict" => return Some(Element::Dict),
-                        "array" => return Some(Element::Array),
+                        "plist" => return Ok(Some(Element::Plist)),
+                        "dict" => return Ok(Some(Element::Dict)),
+                        "array" => return Ok(Some(Element::Array)),
                         "key" => "key",
                         "integer" => "integer",
                         "string" => "string",
-                        "data" => "string",
+                        "data" => "data",
                         "true" => "true",
                         "false" => "false",
                         "date" => "date",
-                        tag => panic!("Unsupported element {:?}", tag),
+                        tag => {
+                            return Err(ParseError::UnexpectedElement {
+                                expected: "plist element",
+                                found: format!("<{}>", tag),
+                                at: self.location(),
+                            })
+                        }
                     };
                 }
                 Ok(XmlEvent::Characters(value)) => contents = Some(value),
                 Ok(XmlEvent::CData(value)) => contents = Some(value),
                 Ok(XmlEvent::EndElement { name, .. }) => {
                     match name.local_name.as_str() {
-                        "plist" => return None,
-                        "dict" => return None,
-                        "array" => return None,
+                        "plist" | "dict" | "array" => {
+                            self.depth -= 1;
+                            return Ok(None);
+                        }
                         _ => break,
                     };
                 }
-                Ok(XmlEvent::EndDocument) => return None,
+                Ok(XmlEvent::EndDocument) => return Ok(None),
                 Ok(_) => {
                     // println!("Skip else: {:?}", elem)
                 }
                 Err(err) => {
-                    panic!("{:?}", err)
+                    let position = err.position();
+                    return Err(ParseError::Xml {
+                        message: err.msg().to_string(),
+                        at: ErrorLocation {
+                            line: position.row + 1,
+                            column: position.column + 1,
+                            context: self.context.clone(),
+                        },
+                    });
                 }
             }
         }
@@ -448,13 +834,53 @@ impl Iterator for ElementsIterator {
             // "plist" => Some(Element::Plist),
             // "dict" => Some(Element::Dict),
             // "array" => Some(Element::Array),
-            "key" => Some(Element::Key(contents.unwrap())),
-            "integer" => Some(Element::Integer(contents.unwrap().parse().unwrap())),
-            "string" => Some(Element::String(contents)),
-            "true" => Some(Element::Boolean(true)),
-            "false" => Some(Element::Boolean(false)),
-            "date" => Some(Element::Date(contents.unwrap())),
-            tag => panic!("Unexpected element {:?}", tag),
+            "key" => Ok(Some(Element::Key(contents.unwrap_or_default()))),
+            "integer" => {
+                let value = contents.unwrap_or_default();
+                match value.trim().parse() {
+                    Ok(i) => Ok(Some(Element::Integer(i))),
+                    Err(_) => Err(ParseError::BadInteger {
+                        value,
+                        at: self.location(),
+                    }),
+                }
+            }
+            "string" => Ok(Some(Element::String(contents))),
+            "data" => {
+                // Payloads are wrapped over several indented lines
+                let value: String = contents
+                    .unwrap_or_default()
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect();
+                match BASE64.decode(value) {
+                    Ok(data) => Ok(Some(Element::Data(data))),
+                    Err(err) => Err(ParseError::BadData {
+                        message: err.to_string(),
+                        at: self.location(),
+                    }),
+                }
+            }
+            "true" => Ok(Some(Element::Boolean(true))),
+            "false" => Ok(Some(Element::Boolean(false))),
+            "date" => match contents {
+                Some(value) => match dates::parse_date(&value) {
+                    Some(date) => Ok(Some(Element::Date(date))),
+                    None => Err(ParseError::BadDate {
+                        value,
+                        at: self.location(),
+                    }),
+                },
+                None => Err(ParseError::MissingValue {
+                    key: self.current_key(),
+                    at: self.location(),
+                }),
+            },
+            tag => Err(ParseError::UnexpectedElement {
+                expected: "plist value",
+                found: format!("<{}>", tag),
+                at: self.location(),
+            }),
         }
     }
 }
@@ -469,4 +895,182 @@ mod tests {
         println!("{:?}", result)
         // assert_eq!(Ok(()), result);
     }
+
+    const SINGLE_TRACK: &str = include_str!("../tests/fixtures/single-track.xml");
+
+    #[test]
+    fn parses_from_memory() {
+        let from_file = parse_itunes_xml("tests/fixtures/single-track.xml").unwrap();
+        let from_str = parse_itunes_xml_str(SINGLE_TRACK).unwrap();
+        let from_reader = parse_itunes_xml_reader(SINGLE_TRACK.as_bytes()).unwrap();
+
+        assert_eq!(from_str.tracks, from_file.tracks);
+        assert_eq!(from_reader.tracks, from_file.tracks);
+        assert_eq!(from_str.tracks[&5994].bpm, None);
+        assert_eq!(from_str.tracks[&5994].total_time, Some(230541));
+        assert_eq!(
+            from_str.tracks[&5994].date_added.map(|date| date.timestamp()),
+            Some(1_479_563_509)
+        );
+    }
+
+    #[test]
+    fn captures_unknown_fields() {
+        let contents = SINGLE_TRACK.replace(
+            "<key>Kind</key>",
+            "<key>Work</key><string>Suite</string>\
+             <key>Start Time</key><integer>1500</integer>\
+             <key>Sort Show</key><string>Show</string>\
+             <key>Kind</key>",
+        );
+
+        let track = &parse_itunes_xml_str(&contents).unwrap().tracks[&5994];
+
+        assert_eq!(track.work.as_deref(), Some("Suite"));
+        assert_eq!(track.start_time, Some(1500));
+        assert_eq!(
+            track.extra.get("Sort Show"),
+            Some(&Element::String(Some("Show".to_string())))
+        );
+        assert_eq!(track.extra.len(), 1);
+    }
+
+    #[test]
+    fn parses_binary_plists() {
+        for name in ["single-track", "Playlist-_lin next party"] {
+            let xml = parse_itunes_xml(&format!("tests/fixtures/{name}.xml")).unwrap();
+            let binary = parse_itunes_xml(&format!("tests/fixtures/{name}.bplist")).unwrap();
+
+            assert_eq!(binary, xml);
+        }
+
+        let bytes = std::fs::read("tests/fixtures/single-track.bplist").unwrap();
+        let library = parse_itunes_xml_bytes(&bytes).unwrap();
+        assert_eq!(library.tracks[&5994].total_time, Some(230541));
+    }
+
+    #[test]
+    fn keeps_playlist_order_and_duplicates() {
+        let item = |id: u64| format!("<key>Track ID</key><integer>{id}</integer>");
+        let (head, tail) = SINGLE_TRACK.rsplit_once(&item(5994)).unwrap();
+        let items = [item(7), item(5994), item(7)].join("</dict><dict>");
+        let contents = format!("{head}{items}{tail}");
+
+        let library = parse_itunes_xml_str(&contents).unwrap();
+
+        assert_eq!(library.playlists[&84983].items, vec![7, 5994, 7]);
+    }
+
+    #[test]
+    fn reports_location_of_bad_integer() {
+        let contents =
+            SINGLE_TRACK.replace("<integer>230541</integer>", "<integer>23o541</integer>");
+
+        match parse_itunes_xml_str(&contents) {
+            Err(ParseError::BadInteger { value, at }) => {
+                assert_eq!(value, "23o541");
+                assert_eq!(at.line, 26);
+                assert_eq!(
+                    at.context,
+                    Context::Track {
+                        id: Some(5994),
+                        key: Some("Total Time".to_string())
+                    }
+                );
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn lenient_skips_malformed_track() {
+        let contents = SINGLE_TRACK
+            .replace("<integer>230541</integer>", "<dict><key>Nested</key><true/></dict>")
+            .replace("<key>All Items</key>", "<key>Unknown Key</key><array/><key>All Items</key>");
+
+        let mut library = Library::default();
+        let report = visit_itunes_xml_reader(contents.as_bytes(), true, &mut library).unwrap();
+
+        assert!(library.tracks.is_empty());
+        assert_eq!(library.playlists[&84983].name, "All");
+        assert!(library.playlists[&84983].all_items);
+        assert_eq!(library.playlists[&84983].items, vec![5994]);
+        assert_eq!(report.skipped_tracks, 1);
+        assert_eq!(report.skipped_playlists, 0);
+        assert_eq!(report.diagnostics.len(), 2);
+        assert_eq!(report.diagnostics[0].track_id, Some(5994));
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("Total Time"));
+        assert_eq!(report.diagnostics[1].key.as_deref(), Some("Unknown Key"));
+        assert!(!report.diagnostics[1].skipped);
+    }
+
+    #[test]
+    fn strict_reports_warnings() {
+        let contents = SINGLE_TRACK.replace(
+            "<key>Total Time</key>",
+            "<key>Custom</key><dict><key>Nested</key><true/></dict><key>Total Time</key>",
+        );
+
+        let mut library = Library::default();
+        let report = visit_itunes_xml_reader(contents.as_bytes(), false, &mut library).unwrap();
+
+        assert_eq!(library.tracks.len(), 1);
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("Custom"));
+    }
+
+    #[test]
+    fn lenient_skips_out_of_range_play_date() {
+        let contents = SINGLE_TRACK.replace(
+            "<integer>3587278628</integer>",
+            "<integer>9223372036854775807</integer>",
+        );
+
+        let mut library = Library::default();
+        let report = visit_itunes_xml_reader(contents.as_bytes(), true, &mut library).unwrap();
+
+        assert!(library.tracks.is_empty());
+        assert_eq!(report.skipped_tracks, 1);
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("Play Date"));
+        assert!(matches!(
+            parse_itunes_xml_str(&contents),
+            Err(ParseError::BadDate { .. })
+        ));
+    }
+
+    #[test]
+    fn visitor_receives_tracks_and_progress() {
+        #[derive(Default)]
+        struct Counter {
+            tracks: usize,
+            playlists: usize,
+            items: Vec<u64>,
+            last_progress: Progress,
+        }
+
+        impl LibraryVisitor for Counter {
+            fn track(&mut self, _track: Track) {
+                self.tracks += 1;
+            }
+
+            fn playlist(&mut self, playlist: Playlist) {
+                self.playlists += 1;
+                self.items = playlist.items;
+            }
+
+            fn progress(&mut self, progress: Progress) {
+                assert!(progress.bytes_read >= self.last_progress.bytes_read);
+                self.last_progress = progress;
+            }
+        }
+
+        let mut counter = Counter::default();
+        visit_itunes_xml("tests/fixtures/Playlist-_lin next party.xml", false, &mut counter)
+            .unwrap();
+
+        assert_eq!(counter.tracks, 54);
+        assert_eq!(counter.items[..3], [6440, 19114, 22852]);
+        assert_eq!(counter.playlists, 1);
+        assert_eq!(counter.last_progress.percent(), Some(100.0));
+    }
 }
@@ -0,0 +1,89 @@
+//! Rewriting of track locations for libraries exported on another machine.
+//!
+//! Locations are `file://` URLs, rules replace a URL prefix and compare percent-encoded text,
+//! e.g. `file:///Users/qu/Music/iTunes/iTunes%20Media/` to `file:///home/qu/Music/`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Library, LibraryInfo};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct LocationRule {
+    /// Prefix to replace, `None` for the library's Music Folder.
+    pub from: Option<String>,
+    pub to: String,
+}
+
+impl LocationRule {
+    /// The prefix this rule replaces, given the Music Folder of the library.
+    pub fn source<'a>(&'a self, info: &'a LibraryInfo) -> Option<&'a str> {
+        self.from.as_deref().or(info.music_folder.as_deref())
+    }
+}
+
+/// Applies the first rule whose source is a prefix of `location`, `None` when none match.
+pub fn remap_location(
+    location: &str,
+    rules: &[LocationRule],
+    info: &LibraryInfo,
+) -> Option<String> {
+    rules.iter().find_map(|rule| {
+        let source = rule.source(info).filter(|source| !source.is_empty())?;
+        let rest = location.strip_prefix(source)?;
+        Some(format!("{}{}", rule.to, rest))
+    })
+}
+
+impl Library {
+    /// Rewrites the location of every track a rule applies to, returning how many changed.
+    pub fn remap_locations(&mut self, rules: &[LocationRule]) -> usize {
+        let info = self.info();
+        let mut remapped = 0;
+        for track in self.tracks.values_mut() {
+            let location = track.location.as_deref();
+            if let Some(location) = location.and_then(|l| remap_location(l, rules, &info)) {
+                track.location = Some(location);
+                remapped += 1;
+            }
+        }
+        remapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_itunes_xml;
+
+    #[test]
+    fn remaps_music_folder_and_explicit_prefixes() {
+        let mut library = parse_itunes_xml("tests/fixtures/single-track.xml").unwrap();
+        let info = library.info();
+        let rules = [
+            LocationRule {
+                from: None,
+                to: "file:///home/lin/Music/".to_string(),
+            },
+            LocationRule {
+                from: Some("file:///path/to/".to_string()),
+                to: "file:///mnt/music/".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            remap_location("file:///path/to/Music/Music/Media/a.mp3", &rules, &info).as_deref(),
+            Some("file:///home/lin/Music/a.mp3")
+        );
+        assert_eq!(
+            remap_location("file:///elsewhere/a.mp3", &rules, &info),
+            None
+        );
+
+        assert_eq!(library.remap_locations(&rules), 1);
+        assert!(library.tracks[&5994]
+            .location
+            .as_deref()
+            .unwrap()
+            .starts_with("file:///mnt/music/file/01%20Bizarre"));
+    }
+}
@@ -0,0 +1,249 @@
+//! Single playlists in M3U, extended M3U8 and PLS files.
+//!
+//! Entries are kept as written, a path relative to the playlist file, an absolute path or a
+//! URL. Resolving them against a library is left to the caller.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum PlaylistFormat {
+    /// Plain list of paths.
+    M3u,
+    /// UTF-8 M3U with `#EXTINF` duration and title.
+    M3u8,
+    Pls,
+}
+
+impl PlaylistFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "m3u" => Some(PlaylistFormat::M3u),
+            "m3u8" => Some(PlaylistFormat::M3u8),
+            "pls" => Some(PlaylistFormat::Pls),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct PlaylistEntry {
+    pub location: String,
+    pub title: Option<String>,
+    /// Seconds, `None` where unknown.
+    pub duration: Option<i64>,
+}
+
+/// Reads the entries in file order. Both M3U flavours accept `#EXTINF` lines.
+pub fn read_playlist_file(text: &str, format: PlaylistFormat) -> Vec<PlaylistEntry> {
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => read_m3u(text),
+        PlaylistFormat::Pls => read_pls(text),
+    }
+}
+
+pub fn write_playlist_file<W: Write>(
+    entries: &[PlaylistEntry],
+    format: PlaylistFormat,
+    mut out: W,
+) -> io::Result<()> {
+    match format {
+        PlaylistFormat::M3u => {
+            for entry in entries {
+                writeln!(out, "{}", single_line(&entry.location))?;
+            }
+        }
+        PlaylistFormat::M3u8 => {
+            writeln!(out, "#EXTM3U")?;
+            for entry in entries {
+                writeln!(
+                    out,
+                    "#EXTINF:{},{}",
+                    entry.duration.unwrap_or(-1),
+                    single_line(entry.title.as_deref().unwrap_or_default())
+                )?;
+                writeln!(out, "{}", single_line(&entry.location))?;
+            }
+        }
+        PlaylistFormat::Pls => {
+            writeln!(out, "[playlist]")?;
+            for (index, entry) in entries.iter().enumerate() {
+                let number = index + 1;
+                writeln!(out, "File{}={}", number, single_line(&entry.location))?;
+                if let Some(title) = &entry.title {
+                    writeln!(out, "Title{}={}", number, single_line(title))?;
+                }
+                writeln!(out, "Length{}={}", number, entry.duration.unwrap_or(-1))?;
+            }
+            writeln!(out, "NumberOfEntries={}", entries.len())?;
+            writeln!(out, "Version=2")?;
+        }
+    }
+    out.flush()
+}
+
+/// Both formats are line based, so a line break inside a value would start a new entry.
+fn single_line(value: &str) -> Cow<'_, str> {
+    match value.contains(['\r', '\n']) {
+        true => Cow::Owned(value.replace("\r\n", " ").replace(['\r', '\n'], " ")),
+        false => Cow::Borrowed(value),
+    }
+}
+
+fn read_m3u(text: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    // `#EXTINF` applies to the next location
+    let mut info: Option<(Option<i64>, Option<String>)> = None;
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let (duration, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+            info = Some((
+                duration_seconds(duration),
+                Some(title.trim().to_string()).filter(|title| !title.is_empty()),
+            ));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let (duration, title) = info.take().unwrap_or_default();
+            entries.push(PlaylistEntry {
+                location: line.to_string(),
+                title,
+                duration,
+            });
+        }
+    }
+    entries
+}
+
+fn read_pls(text: &str) -> Vec<PlaylistEntry> {
+    // Keyed by entry number, which need not be contiguous or in order
+    let mut entries: BTreeMap<u64, PlaylistEntry> = BTreeMap::new();
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let field = key.trim_end_matches(|c: char| c.is_ascii_digit());
+        let Ok(number) = key[field.len()..].parse::<u64>() else {
+            continue;
+        };
+        let entry = entries.entry(number).or_default();
+        match field.to_ascii_lowercase().as_str() {
+            "file" => entry.location = value.to_string(),
+            "title" => entry.title = Some(value.to_string()).filter(|title| !title.is_empty()),
+            "length" => entry.duration = duration_seconds(value),
+            _ => (),
+        }
+    }
+    entries
+        .into_values()
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+/// -1 stands for an unknown length, e.g. of a stream.
+fn duration_seconds(value: &str) -> Option<i64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds >= 0.0)
+        .map(|seconds| seconds.round() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<PlaylistEntry> {
+        vec![
+            PlaylistEntry {
+                location: "/Users/lin/Music/Bizarre Love Triangle.m4a".to_string(),
+                title: Some("New Order - Bizarre Love Triangle".to_string()),
+                duration: Some(230),
+            },
+            PlaylistEntry {
+                location: "../Music/Blue Monday.mp3".to_string(),
+                title: None,
+                duration: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        for format in [PlaylistFormat::M3u8, PlaylistFormat::Pls] {
+            let mut buffer = Vec::new();
+            write_playlist_file(&entries(), format, &mut buffer).unwrap();
+            let text = String::from_utf8(buffer).unwrap();
+
+            assert_eq!(read_playlist_file(&text, format), entries(), "{:?}", format);
+        }
+
+        let mut buffer = Vec::new();
+        write_playlist_file(&entries(), PlaylistFormat::M3u, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let locations: Vec<String> = read_playlist_file(&text, PlaylistFormat::M3u)
+            .into_iter()
+            .map(|entry| entry.location)
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "/Users/lin/Music/Bizarre Love Triangle.m4a",
+                "../Music/Blue Monday.mp3"
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_files_written_elsewhere() {
+        let m3u = "\u{feff}#EXTM3U\r\n\r\n#EXTINF:229.9, Regret\r\nC:\\Music\\Regret.wav\r\n\
+            # comment\r\nhttp://radio.example/stream\r\n";
+        let entries = read_playlist_file(m3u, PlaylistFormat::M3u);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "C:\\Music\\Regret.wav");
+        assert_eq!(entries[0].title.as_deref(), Some("Regret"));
+        assert_eq!(entries[0].duration, Some(230));
+        assert_eq!(entries[1].duration, None);
+
+        let pls = "[playlist]\nFile2=b.mp3\nTitle1=A\nFile1=a.mp3\nLength1=-1\n\
+            NumberOfEntries=2\nVersion=2\n";
+        let entries = read_playlist_file(pls, PlaylistFormat::Pls);
+        let locations: Vec<&str> = entries.iter().map(|e| e.location.as_str()).collect();
+        assert_eq!(locations, vec!["a.mp3", "b.mp3"]);
+        assert_eq!(entries[0].title.as_deref(), Some("A"));
+        assert_eq!(entries[0].duration, None);
+
+        assert_eq!(
+            PlaylistFormat::from_path(Path::new("Party.M3U8")),
+            Some(PlaylistFormat::M3u8)
+        );
+    }
+
+    #[test]
+    fn keeps_line_breaks_out_of_entries() {
+        let entries = vec![PlaylistEntry {
+            location: "/Music/Two\nLines.mp3".to_string(),
+            title: Some("Two\r\nLines".to_string()),
+            duration: Some(60),
+        }];
+        for format in [
+            PlaylistFormat::M3u,
+            PlaylistFormat::M3u8,
+            PlaylistFormat::Pls,
+        ] {
+            let mut buffer = Vec::new();
+            write_playlist_file(&entries, format, &mut buffer).unwrap();
+            let text = String::from_utf8(buffer).unwrap();
+            let read = read_playlist_file(&text, format);
+
+            assert_eq!(read.len(), 1, "{:?}", format);
+            assert_eq!(read[0].location, "/Music/Two Lines.mp3");
+        }
+    }
+}
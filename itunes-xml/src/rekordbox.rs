@@ -0,0 +1,474 @@
+//! Import of Rekordbox collection exports (`DJ_PLAYLISTS` XML).
+//!
+//! ```xml
+//! <DJ_PLAYLISTS Version="1.0.0">
+//!   <COLLECTION Entries="1">
+//!     <TRACK TrackID="1" Name="..." AverageBpm="128.00" TotalTime="230" Location="file://localhost/...">
+//!       <TEMPO Inizio="0.025" Bpm="128.00" Metro="4/4" Battito="1"/>
+//!       <POSITION_MARK Name="" Type="0" Start="0.025" Num="-1"/>
+//!     </TRACK>
+//!   </COLLECTION>
+//!   <PLAYLISTS>
+//!     <NODE Type="0" Name="ROOT">
+//!       <NODE Type="1" Name="List" KeyType="0"><TRACK Key="1"/></NODE>
+//!     </NODE>
+//!   </PLAYLISTS>
+//! </DJ_PLAYLISTS>
+//! ```
+//!
+//! Tracks map onto the iTunes model: seconds become milliseconds, ratings of 0-255 become 0-100,
+//! `NODE` folders become folder playlists. Attributes without a `Track` field go to `extra`.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+use xml::attribute::OwnedAttribute;
+use xml::reader::XmlEvent;
+
+use crate::attributes::{attribute, AttributeReader, NodeIds};
+use crate::error::Context;
+use crate::{Element, Library, LibraryVisitor, ParseError, ParseReport, Playlist, Track};
+
+/// A beat grid section, `<TEMPO>`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct TempoMarker {
+    /// Seconds from the start of the track.
+    pub start: f64,
+    pub bpm: f64,
+    /// Time signature, e.g. `4/4`.
+    pub meter: String,
+    /// Position of the marker within the bar, starting at 1.
+    pub beat: i64,
+}
+
+/// A cue point or loop, `<POSITION_MARK>`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct CuePoint {
+    pub name: String,
+    /// 0 cue, 1 fade-in, 2 fade-out, 3 load, 4 loop.
+    pub kind: i64,
+    /// Seconds from the start of the track.
+    pub start: f64,
+    /// End of a loop.
+    pub end: Option<f64>,
+    /// Hot cue slot, -1 for memory cues.
+    pub number: i64,
+    pub color: Option<[u8; 3]>,
+}
+
+/// Attributes with a dedicated `Track` field, everything else is kept in `extra`.
+const TRACK_ATTRIBUTES: [&str; 21] = [
+    "TrackID",
+    "Name",
+    "Artist",
+    "Composer",
+    "Album",
+    "Grouping",
+    "Genre",
+    "Kind",
+    "Size",
+    "TotalTime",
+    "DiscNumber",
+    "TrackNumber",
+    "Year",
+    "AverageBpm",
+    "DateAdded",
+    "BitRate",
+    "SampleRate",
+    "Comments",
+    "PlayCount",
+    "Rating",
+    "Location",
+];
+
+/// Whether the start of a document is a Rekordbox export rather than an iTunes plist.
+pub fn is_rekordbox_xml(head: &[u8]) -> bool {
+    head.windows(b"<DJ_PLAYLISTS".len())
+        .any(|window| window == b"<DJ_PLAYLISTS")
+}
+
+pub fn parse_rekordbox_xml(file_path: &str) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    visit_rekordbox_xml(file_path, false, &mut library)?;
+    Ok(library)
+}
+
+pub fn parse_rekordbox_xml_reader<R: Read>(reader: R) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    visit_rekordbox_xml_reader(reader, false, &mut library)?;
+    Ok(library)
+}
+
+/// Streams a Rekordbox export to `visitor`, like [`crate::visit_itunes_xml`].
+pub fn visit_rekordbox_xml<V: LibraryVisitor>(
+    file_path: &str,
+    lenient: bool,
+    visitor: &mut V,
+) -> Result<ParseReport, ParseError> {
+    RekordboxReader {
+        xml: AttributeReader::open(file_path, lenient)?,
+    }
+    .read(visitor)
+}
+
+pub fn visit_rekordbox_xml_reader<R: Read, V: LibraryVisitor>(
+    reader: R,
+    lenient: bool,
+    visitor: &mut V,
+) -> Result<ParseReport, ParseError> {
+    RekordboxReader {
+        xml: AttributeReader::new(reader, None, lenient),
+    }
+    .read(visitor)
+}
+
+struct RekordboxReader<R: Read> {
+    xml: AttributeReader<R>,
+}
+
+impl<R: Read> RekordboxReader<R> {
+    fn read<V: LibraryVisitor>(mut self, visitor: &mut V) -> Result<ParseReport, ParseError> {
+        let mut in_collection = false;
+        // Collection track being read, `None` inside a skipped one
+        let mut track: Option<Option<Track>> = None;
+        let mut ids_by_location: HashMap<String, u64> = HashMap::new();
+        // Open playlist nodes and whether their tracks are keyed by location, `None` for ROOT
+        let mut nodes: Vec<Option<(Playlist, bool)>> = Vec::new();
+        let mut next_playlist_id = 1;
+        let mut node_ids = NodeIds::new("RB");
+
+        loop {
+            let event = self.xml.next()?;
+            match event {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => match name.local_name.as_str() {
+                    "PRODUCT" => {
+                        for key in ["Name", "Version", "Company"] {
+                            if let Some(value) = attribute(&attributes, key) {
+                                let value = Element::String(Some(value.to_string()));
+                                visitor.metadata(format!("Product {}", key), value);
+                            }
+                        }
+                    }
+                    "COLLECTION" => in_collection = true,
+                    "TRACK" if in_collection => match self.read_track(&attributes) {
+                        Ok(read) => track = Some(Some(read)),
+                        Err(err) => {
+                            self.xml.skip_track(err)?;
+                            track = Some(None);
+                        }
+                    },
+                    "TEMPO" => {
+                        if let Some(Some(track)) = &mut track {
+                            match self.read_tempo(&attributes) {
+                                Ok(marker) => track.tempo_markers.push(marker),
+                                Err(err) => self.xml.skip_marker(err)?,
+                            }
+                        }
+                    }
+                    "POSITION_MARK" => {
+                        if let Some(Some(track)) = &mut track {
+                            match self.read_cue(&attributes) {
+                                Ok(cue) => track.cue_points.push(cue),
+                                Err(err) => self.xml.skip_marker(err)?,
+                            }
+                        }
+                    }
+                    "NODE" => {
+                        let name = attribute(&attributes, "Name").unwrap_or_default();
+                        if nodes.is_empty() && name == "ROOT" {
+                            nodes.push(None);
+                            continue;
+                        }
+                        let id = next_playlist_id;
+                        next_playlist_id += 1;
+                        let path = nodes.iter().flatten().map(|(node, _)| node.name.as_str());
+                        let playlist = Playlist {
+                            id,
+                            name: name.to_string(),
+                            persistent_id: node_ids.next(path.chain([name])),
+                            parent_persistent_id: nodes
+                                .iter()
+                                .rev()
+                                .flatten()
+                                .next()
+                                .map(|(parent, _)| parent.persistent_id.clone()),
+                            folder: Some(attribute(&attributes, "Type") == Some("0")),
+                            ..Default::default()
+                        };
+                        let by_location = attribute(&attributes, "KeyType") == Some("1");
+                        nodes.push(Some((playlist, by_location)));
+                    }
+                    "TRACK" => {
+                        let Some(Some((playlist, by_location))) = nodes.last_mut() else {
+                            continue;
+                        };
+                        let key = attribute(&attributes, "Key").unwrap_or_default();
+                        let id = match by_location {
+                            true => ids_by_location.get(key).copied(),
+                            false => key.parse().ok(),
+                        };
+                        match id {
+                            Some(id) => playlist.items.push(id),
+                            None => {
+                                let reason = format!("Unknown track {:?}", key);
+                                self.xml.context = Context::Playlist {
+                                    name: Some(playlist.name.clone()),
+                                    key: Some("Key".to_string()),
+                                };
+                                self.xml.warn(reason);
+                                self.xml.context = Context::Document;
+                            }
+                        }
+                    }
+                    _ => (),
+                },
+                XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                    "COLLECTION" => in_collection = false,
+                    "TRACK" if in_collection => {
+                        if let Some(Some(track)) = track.take() {
+                            if let Some(location) = &track.location {
+                                ids_by_location.insert(location.clone(), track.id);
+                            }
+                            visitor.track(track);
+                            visitor.progress(self.xml.progress());
+                        }
+                        self.xml.context = Context::Document;
+                    }
+                    "NODE" => {
+                        if let Some(Some((playlist, _))) = nodes.pop() {
+                            visitor.playlist(playlist);
+                            visitor.progress(self.xml.progress());
+                        }
+                    }
+                    _ => (),
+                },
+                XmlEvent::EndDocument => break,
+                _ => (),
+            }
+        }
+
+        Ok(self.xml.report)
+    }
+
+    fn read_track(&mut self, attributes: &[OwnedAttribute]) -> Result<Track, ParseError> {
+        self.xml.context = Context::Track {
+            id: None,
+            key: Some("TrackID".to_string()),
+        };
+        let id =
+            self.xml
+                .integer(attributes, "TrackID")?
+                .ok_or_else(|| ParseError::MissingValue {
+                    key: "TrackID".to_string(),
+                    at: self.xml.location(),
+                })?;
+        let id = u64::try_from(id).map_err(|_| ParseError::BadInteger {
+            value: id.to_string(),
+            at: self.xml.location(),
+        })?;
+        self.xml.context = Context::Track {
+            id: Some(id),
+            key: None,
+        };
+
+        let string = |key: &str| {
+            attribute(attributes, key)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        // Rekordbox writes 0 for unknown numbers
+        let nonzero = |value: Option<i64>| value.filter(|value| *value != 0);
+
+        let mut track = Track {
+            id,
+            name: string("Name"),
+            artist: string("Artist"),
+            composer: string("Composer"),
+            album: string("Album"),
+            grouping: string("Grouping"),
+            genre: string("Genre"),
+            kind: string("Kind"),
+            comments: string("Comments"),
+            location: string("Location"),
+            size: self.xml.integer(attributes, "Size")?,
+            total_time: self.xml.scaled_integer(attributes, "TotalTime", 1000)?,
+            disc_number: nonzero(self.xml.integer(attributes, "DiscNumber")?),
+            track_number: nonzero(self.xml.integer(attributes, "TrackNumber")?),
+            year: nonzero(self.xml.integer(attributes, "Year")?),
+            bpm: self
+                .xml
+                .real(attributes, "AverageBpm")?
+                .map(|bpm| bpm.round() as i64),
+            bit_rate: self.xml.integer(attributes, "BitRate")?,
+            sample_rate: self.xml.integer(attributes, "SampleRate")?,
+            play_count: self.xml.integer(attributes, "PlayCount")?,
+            rating: nonzero(self.xml.scaled_integer(attributes, "Rating", 100)?)
+                .map(|rating| rating / 255),
+            date_added: self.xml.date(attributes, "DateAdded", "%Y-%m-%d")?,
+            ..Default::default()
+        };
+        for attribute in attributes {
+            let key = attribute.name.local_name.as_str();
+            if !TRACK_ATTRIBUTES.contains(&key) && !attribute.value.is_empty() {
+                let value = Element::String(Some(attribute.value.clone()));
+                track.extra.insert(key.to_string(), value);
+            }
+        }
+        Ok(track)
+    }
+
+    fn read_tempo(&mut self, attributes: &[OwnedAttribute]) -> Result<TempoMarker, ParseError> {
+        Ok(TempoMarker {
+            start: self.xml.real(attributes, "Inizio")?.unwrap_or_default(),
+            bpm: self.xml.real(attributes, "Bpm")?.unwrap_or_default(),
+            meter: attribute(attributes, "Metro").unwrap_or("4/4").to_string(),
+            beat: self.xml.integer(attributes, "Battito")?.unwrap_or(1),
+        })
+    }
+
+    fn read_cue(&mut self, attributes: &[OwnedAttribute]) -> Result<CuePoint, ParseError> {
+        let color = match (
+            self.xml.integer(attributes, "Red")?,
+            self.xml.integer(attributes, "Green")?,
+            self.xml.integer(attributes, "Blue")?,
+        ) {
+            (Some(red), Some(green), Some(blue)) => Some([red as u8, green as u8, blue as u8]),
+            _ => None,
+        };
+        Ok(CuePoint {
+            name: attribute(attributes, "Name")
+                .unwrap_or_default()
+                .to_string(),
+            kind: self.xml.integer(attributes, "Type")?.unwrap_or_default(),
+            start: self.xml.real(attributes, "Start")?.unwrap_or_default(),
+            end: self.xml.real(attributes, "End")?,
+            number: self.xml.integer(attributes, "Num")?.unwrap_or(-1),
+            color,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_collection_and_playlists() {
+        let library = parse_rekordbox_xml("tests/fixtures/rekordbox.xml").unwrap();
+
+        assert_eq!(library.tracks.len(), 3);
+        let track = &library.tracks[&12345];
+        assert_eq!(track.name.as_deref(), Some("Bizarre Love Triangle"));
+        assert_eq!(
+            track.composer.as_deref(),
+            Some("Stephen Morris & Bernard Sumner")
+        );
+        assert_eq!(track.total_time, Some(230_000));
+        assert_eq!(track.bpm, Some(121));
+        assert_eq!(track.rating, Some(80));
+        assert_eq!(track.disc_number, None);
+        assert_eq!(track.date_added.unwrap().timestamp(), 1_615_680_000);
+        assert_eq!(track.tempo_markers.len(), 2);
+        assert_eq!(track.tempo_markers[1].bpm, 121.5);
+        assert_eq!(track.cue_points[0].name, "Intro");
+        assert_eq!(track.cue_points[0].color, Some([40, 226, 20]));
+        assert_eq!(track.cue_points[1].end, Some(38.055));
+        assert_eq!(
+            track.extra.get("Tonality"),
+            Some(&Element::String(Some("Fm".to_string())))
+        );
+
+        let tree = library.playlist_tree();
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].name, "Gigs");
+        assert!(tree[0].folder);
+        assert_eq!(tree[0].children[0].name, "Club X");
+        let club = &library.playlists[&tree[0].children[0].id];
+        assert!(club.persistent_id.starts_with("RB"));
+        assert_eq!(
+            club.parent_persistent_id,
+            Some(tree[0].persistent_id.clone())
+        );
+        assert_eq!(club.items, vec![12346, 12345, 12346]);
+        assert_eq!(library.playlists[&tree[1].id].items, vec![12347]);
+        assert_eq!(
+            library.metadata.get("Product Version"),
+            Some(&Element::String(Some("6.7.4".to_string())))
+        );
+    }
+
+    #[test]
+    fn detects_format() {
+        let rekordbox = std::fs::read("tests/fixtures/rekordbox.xml").unwrap();
+        let itunes = std::fs::read("tests/fixtures/single-track.xml").unwrap();
+
+        assert!(is_rekordbox_xml(&rekordbox[..256]));
+        assert!(!is_rekordbox_xml(&itunes[..256]));
+    }
+
+    #[test]
+    fn skips_malformed_tracks_when_lenient() {
+        let xml = std::fs::read_to_string("tests/fixtures/rekordbox.xml")
+            .unwrap()
+            .replace("TotalTime=\"449\"", "TotalTime=\"long\"");
+        let mut library = Library::default();
+
+        let report = visit_rekordbox_xml_reader(xml.as_bytes(), true, &mut library).unwrap();
+
+        assert_eq!(report.skipped_tracks, 1);
+        assert_eq!(report.diagnostics[0].track_id, Some(12346));
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("TotalTime"));
+        assert_eq!(library.tracks.len(), 2);
+        assert!(parse_rekordbox_xml_reader(xml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn skips_malformed_markers_when_lenient() {
+        let xml = std::fs::read_to_string("tests/fixtures/rekordbox.xml")
+            .unwrap()
+            .replace("Start=\"0.120\"", "Start=\"soon\"");
+        let mut library = Library::default();
+
+        let report = visit_rekordbox_xml_reader(xml.as_bytes(), true, &mut library).unwrap();
+
+        assert_eq!(report.skipped_tracks, 0);
+        assert_eq!(report.diagnostics.len(), 1);
+        assert!(!report.diagnostics[0].skipped);
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("Start"));
+        let track = &library.tracks[&12345];
+        assert_eq!(track.cue_points.len(), 1);
+        assert_eq!(track.cue_points[0].start, 30.12);
+        assert!(parse_rekordbox_xml_reader(xml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_units() {
+        let xml = std::fs::read_to_string("tests/fixtures/rekordbox.xml")
+            .unwrap()
+            .replace("TotalTime=\"449\"", "TotalTime=\"9223372036854775807\"");
+        let mut library = Library::default();
+
+        let report = visit_rekordbox_xml_reader(xml.as_bytes(), true, &mut library).unwrap();
+
+        assert_eq!(report.skipped_tracks, 1);
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("TotalTime"));
+        assert_eq!(library.tracks.len(), 2);
+    }
+
+    #[test]
+    fn rejects_negative_track_ids() {
+        let xml = std::fs::read_to_string("tests/fixtures/rekordbox.xml")
+            .unwrap()
+            .replace("TrackID=\"12347\"", "TrackID=\"-12347\"");
+        let mut library = Library::default();
+
+        let report = visit_rekordbox_xml_reader(xml.as_bytes(), true, &mut library).unwrap();
+
+        assert_eq!(report.skipped_tracks, 1);
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("TrackID"));
+        assert_eq!(library.tracks.len(), 2);
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use xml::escape::escape_str_attribute;
+
+use crate::tree::playlist_tree;
+use crate::{Element, Library, PlaylistNode, Track};
+
+/// The `extra` keys written back as `TRACK` attributes. Others, like the iTunes "Sort Name",
+/// are no Rekordbox attributes and may not even be valid XML names.
+const EXTRA_ATTRIBUTES: [&str; 4] = ["Label", "Mix", "Remixer", "Tonality"];
+
+const HEADER: &str =
+    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\n<DJ_PLAYLISTS Version=\"1.0.0\">\n";
+
+/// Writes the playlists with the given IDs as a Rekordbox collection, folders with everything
+/// below them. The collection holds only the tracks these playlists contain, playlist entries
+/// of tracks missing from the library are left out.
+pub fn write_rekordbox_xml<W: Write>(
+    library: &Library,
+    playlist_ids: &[u64],
+    mut out: W,
+) -> io::Result<()> {
+    let tree = playlist_tree(library.playlists.values());
+    let mut nodes: Vec<&PlaylistNode> = Vec::new();
+    for id in playlist_ids {
+        if let Some(node) = tree.iter().find_map(|node| find(node, *id)) {
+            if !nodes.iter().any(|selected| selected.id == node.id) {
+                nodes.push(node);
+            }
+        }
+    }
+    // Playlists in a selected folder are written with the folder
+    let selected = nodes.clone();
+    nodes.retain(|node| {
+        !selected
+            .iter()
+            .any(|folder| folder.id != node.id && find(folder, node.id).is_some())
+    });
+    let track_ids: BTreeSet<u64> = nodes
+        .iter()
+        .flat_map(|node| &node.track_ids)
+        .filter(|id| library.tracks.contains_key(id))
+        .copied()
+        .collect();
+
+    out.write_all(HEADER.as_bytes())?;
+    writeln!(
+        out,
+        "  <PRODUCT Name=\"itunes-xml\" Version=\"{}\" Company=\"\"/>",
+        env!("CARGO_PKG_VERSION")
+    )?;
+
+    writeln!(out, "  <COLLECTION Entries=\"{}\">", track_ids.len())?;
+    for id in &track_ids {
+        write_track(&mut out, &library.tracks[id])?;
+    }
+    writeln!(out, "  </COLLECTION>")?;
+
+    writeln!(out, "  <PLAYLISTS>")?;
+    writeln!(
+        out,
+        "    <NODE Type=\"0\" Name=\"ROOT\" Count=\"{}\">",
+        nodes.len()
+    )?;
+    for node in nodes {
+        write_node(&mut out, library, &track_ids, node, 3)?;
+    }
+    writeln!(out, "    </NODE>")?;
+    writeln!(out, "  </PLAYLISTS>")?;
+    writeln!(out, "</DJ_PLAYLISTS>")?;
+    out.flush()
+}
+
+pub fn to_rekordbox_xml_string(library: &Library, playlist_ids: &[u64]) -> String {
+    let mut buffer = Vec::new();
+    write_rekordbox_xml(library, playlist_ids, &mut buffer).expect("Writing to a Vec never fails");
+    String::from_utf8(buffer).expect("Escaped XML is valid UTF-8")
+}
+
+fn find(node: &PlaylistNode, id: u64) -> Option<&PlaylistNode> {
+    if node.id == id {
+        return Some(node);
+    }
+    node.children.iter().find_map(|child| find(child, id))
+}
+
+fn write_track<W: Write>(out: &mut W, track: &Track) -> io::Result<()> {
+    let mut attributes = vec![("TrackID", track.id.to_string())];
+    let strings = [
+        ("Name", &track.name),
+        ("Artist", &track.artist),
+        ("Composer", &track.composer),
+        ("Album", &track.album),
+        ("Grouping", &track.grouping),
+        ("Genre", &track.genre),
+        ("Kind", &track.kind),
+    ];
+    for (key, value) in strings {
+        if let Some(value) = value {
+            attributes.push((key, value.clone()));
+        }
+    }
+    let numbers = [
+        ("Size", track.size),
+        ("TotalTime", track.total_time.map(|time| time / 1000)),
+        ("DiscNumber", track.disc_number),
+        ("TrackNumber", track.track_number),
+        ("Year", track.year),
+    ];
+    for (key, value) in numbers {
+        if let Some(value) = value {
+            attributes.push((key, value.to_string()));
+        }
+    }
+    if let Some(bpm) = track.bpm {
+        attributes.push(("AverageBpm", format!("{:.2}", bpm as f64)));
+    }
+    if let Some(date) = track.date_added {
+        attributes.push(("DateAdded", date.format("%Y-%m-%d").to_string()));
+    }
+    let numbers = [
+        ("BitRate", track.bit_rate),
+        ("SampleRate", track.sample_rate),
+    ];
+    for (key, value) in numbers {
+        if let Some(value) = value {
+            attributes.push((key, value.to_string()));
+        }
+    }
+    if let Some(comments) = &track.comments {
+        attributes.push(("Comments", comments.clone()));
+    }
+    let numbers = [
+        ("PlayCount", track.play_count),
+        (
+            "Rating",
+            track.rating.map(|rating| rating.clamp(0, 100) * 255 / 100),
+        ),
+    ];
+    for (key, value) in numbers {
+        if let Some(value) = value {
+            attributes.push((key, value.to_string()));
+        }
+    }
+    if let Some(location) = &track.location {
+        attributes.push(("Location", rekordbox_location(location)));
+    }
+    // From an earlier Rekordbox or Traktor import
+    for key in EXTRA_ATTRIBUTES {
+        if let Some(Element::String(Some(value))) = track.extra.get(key) {
+            attributes.push((key, value.clone()));
+        }
+    }
+
+    write!(out, "    <TRACK")?;
+    write_attributes(out, &attributes)?;
+    if track.tempo_markers.is_empty() && track.cue_points.is_empty() {
+        return writeln!(out, "/>");
+    }
+    writeln!(out, ">")?;
+    for marker in &track.tempo_markers {
+        write!(out, "      <TEMPO")?;
+        write_attributes(
+            out,
+            &[
+                ("Inizio", format!("{:.3}", marker.start)),
+                ("Bpm", format!("{:.2}", marker.bpm)),
+                ("Metro", marker.meter.clone()),
+                ("Battito", marker.beat.to_string()),
+            ],
+        )?;
+        writeln!(out, "/>")?;
+    }
+    for cue in &track.cue_points {
+        let mut attributes = vec![
+            ("Name", cue.name.clone()),
+            ("Type", cue.kind.to_string()),
+            ("Start", format!("{:.3}", cue.start)),
+        ];
+        if let Some(end) = cue.end {
+            attributes.push(("End", format!("{:.3}", end)));
+        }
+        attributes.push(("Num", cue.number.to_string()));
+        if let Some([red, green, blue]) = cue.color {
+            attributes.push(("Red", red.to_string()));
+            attributes.push(("Green", green.to_string()));
+            attributes.push(("Blue", blue.to_string()));
+        }
+        write!(out, "      <POSITION_MARK")?;
+        write_attributes(out, &attributes)?;
+        writeln!(out, "/>")?;
+    }
+    writeln!(out, "    </TRACK>")
+}
+
+fn write_node<W: Write>(
+    out: &mut W,
+    library: &Library,
+    track_ids: &BTreeSet<u64>,
+    node: &PlaylistNode,
+    level: usize,
+) -> io::Result<()> {
+    let indent = "  ".repeat(level);
+    let name = escape_str_attribute(&node.name);
+    if node.folder {
+        writeln!(
+            out,
+            "{indent}<NODE Name=\"{name}\" Type=\"0\" Count=\"{}\">",
+            node.children.len()
+        )?;
+        for child in &node.children {
+            write_node(out, library, track_ids, child, level + 1)?;
+        }
+        return writeln!(out, "{indent}</NODE>");
+    }
+
+    // Keep the playlist order and duplicates, `track_ids` is deduplicated
+    let items: Vec<u64> = library
+        .playlists
+        .get(&node.id)
+        .map_or(&node.track_ids, |playlist| &playlist.items)
+        .iter()
+        .filter(|id| track_ids.contains(id))
+        .copied()
+        .collect();
+    writeln!(
+        out,
+        "{indent}<NODE Name=\"{name}\" Type=\"1\" KeyType=\"0\" Entries=\"{}\">",
+        items.len()
+    )?;
+    for id in items {
+        writeln!(out, "{indent}  <TRACK Key=\"{id}\"/>")?;
+    }
+    writeln!(out, "{indent}</NODE>")
+}
+
+fn write_attributes<W: Write>(out: &mut W, attributes: &[(&str, String)]) -> io::Result<()> {
+    for (key, value) in attributes {
+        write!(out, " {}=\"{}\"", key, escape_str_attribute(value))?;
+    }
+    Ok(())
+}
+
+/// Rekordbox expects `file://localhost/` where iTunes writes `file:///`.
+fn rekordbox_location(location: &str) -> String {
+    match location.strip_prefix("file:///") {
+        Some(path) => format!("file://localhost/{}", path),
+        None => location.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_itunes_xml, parse_rekordbox_xml, parse_rekordbox_xml_reader};
+
+    #[test]
+    fn round_trips_rekordbox_fixture() {
+        let library = parse_rekordbox_xml("tests/fixtures/rekordbox.xml").unwrap();
+        let roots: Vec<u64> = library.playlist_tree().iter().map(|node| node.id).collect();
+
+        let xml = to_rekordbox_xml_string(&library, &roots);
+        let reparsed = parse_rekordbox_xml_reader(xml.as_bytes()).unwrap();
+
+        assert_eq!(reparsed.tracks, library.tracks);
+        assert_eq!(reparsed.playlists, library.playlists);
+        assert!(xml.contains("Composer=\"Stephen Morris &amp; Bernard Sumner\""));
+    }
+
+    #[test]
+    fn exports_itunes_library() {
+        let library = parse_itunes_xml("tests/fixtures/Playlist-_lin next party.xml").unwrap();
+        let roots: Vec<u64> = library.playlist_tree().iter().map(|node| node.id).collect();
+
+        let xml = to_rekordbox_xml_string(&library, &roots);
+        let reparsed = parse_rekordbox_xml_reader(xml.as_bytes()).unwrap();
+
+        assert!(!xml.contains("Sort Name"));
+        assert_eq!(reparsed.tracks.len(), library.tracks.len());
+        for (id, track) in &reparsed.tracks {
+            assert_eq!(track.name, library.tracks[id].name);
+        }
+    }
+
+    #[test]
+    fn exports_selected_playlists_only() {
+        let library = parse_rekordbox_xml("tests/fixtures/rekordbox.xml").unwrap();
+        let club = library.playlist_tree()[0].children[0].id;
+
+        let xml = to_rekordbox_xml_string(&library, &[club]);
+
+        assert!(xml.contains("<COLLECTION Entries=\"2\">"));
+        assert!(xml.contains("      <NODE Name=\"Club X\" Type=\"1\" KeyType=\"0\" Entries=\"3\">"));
+        assert!(!xml.contains("Regret"));
+    }
+
+    #[test]
+    fn writes_nested_selections_and_missing_tracks_once() {
+        let mut library = parse_rekordbox_xml("tests/fixtures/rekordbox.xml").unwrap();
+        let gigs = library.playlist_tree()[0].id;
+        let club = library.playlist_tree()[0].children[0].id;
+        library.tracks.get_mut(&12345).unwrap().rating = Some(i64::MAX);
+        library.tracks.remove(&12346);
+
+        let xml = to_rekordbox_xml_string(&library, &[club, gigs, club]);
+
+        assert_eq!(xml.matches("Name=\"Club X\"").count(), 1);
+        assert!(xml.contains("<NODE Type=\"0\" Name=\"ROOT\" Count=\"1\">"));
+        assert!(xml.contains("<COLLECTION Entries=\"1\">"));
+        assert!(xml.contains("Rating=\"255\""));
+        assert!(!xml.contains("Key=\"12346\""));
+        assert!(parse_rekordbox_xml_reader(xml.as_bytes()).is_ok());
+    }
+}
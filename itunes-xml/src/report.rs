@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Context, ErrorLocation, ParseError};
+
+/// Problems found while parsing in lenient mode.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ParseReport {
+    pub skipped_tracks: usize,
+    pub skipped_playlists: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct Diagnostic {
+    pub line: u64,
+    pub column: u64,
+    pub track_id: Option<u64>,
+    pub playlist: Option<String>,
+    pub key: Option<String>,
+    pub reason: String,
+    /// The enclosing track or playlist was dropped because of this problem.
+    pub skipped: bool,
+}
+
+impl Diagnostic {
+    pub(crate) fn skipped(err: &ParseError) -> Self {
+        let reason = match err {
+            ParseError::UnexpectedElement {
+                expected, found, ..
+            } => {
+                format!("Expected {expected}, found {found}")
+            }
+            ParseError::BadInteger { value, .. } => format!("Bad integer {value:?}"),
+            ParseError::BadReal { value, .. } => format!("Bad real {value:?}"),
+            ParseError::BadDate { value, .. } => format!("Bad date {value:?}"),
+            ParseError::MissingValue { key, .. } => format!("Missing value for {key:?}"),
+            err => err.to_string(),
+        };
+        let location = err.location().cloned().unwrap_or_default();
+        Diagnostic {
+            skipped: true,
+            ..Diagnostic::warning(&location, reason)
+        }
+    }
+
+    pub(crate) fn warning(location: &ErrorLocation, reason: String) -> Self {
+        let (track_id, playlist, key) = match &location.context {
+            Context::Document => (None, None, None),
+            Context::Track { id, key } => (*id, None, key.clone()),
+            Context::Playlist { name, key } => (None, name.clone(), key.clone()),
+        };
+        Diagnostic {
+            line: location.line,
+            column: location.column,
+            track_id,
+            playlist,
+            key,
+            reason,
+            skipped: false,
+        }
+    }
+}
@@ -0,0 +1,715 @@
+//! Decoding of the `Smart Info` and `Smart Criteria` blobs of iTunes smart playlists.
+//!
+//! `Smart Info` holds the playlist options (live updating, limits), `Smart Criteria` is an
+//! `SLst` structure with the rules. Both are big-endian:
+//!
+//! ```text
+//! SLst header (136 bytes): magic, version, rule count (u32 @ 8), any/all (u32 @ 12)
+//! rule: field (u32), operator (u32), 44 bytes padding, data length (u32), data
+//! ```
+//!
+//! String rule data is UTF-16BE, other rules store 64-bit integers, and nested rule groups
+//! embed another `SLst` structure.
+
+use std::fmt;
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::dates::from_mac_seconds;
+use crate::{Playlist, Track};
+
+const SLST_MAGIC: &[u8] = b"SLst";
+const HEADER_LENGTH: usize = 136;
+const RULE_HEADER_LENGTH: usize = 56;
+const INFO_LENGTH: usize = 14;
+
+// Operator flags (high byte)
+const STRING_FLAG: u32 = 0x0100_0000;
+const NEGATED_FLAG: u32 = 0x0200_0000;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SmartPlaylist {
+    pub live_updating: bool,
+    /// When `false` the criteria are ignored and only the limit applies.
+    pub match_rules: bool,
+    pub limit: Option<Limit>,
+    pub criteria: Criteria,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Limit {
+    pub amount: u32,
+    pub unit: LimitUnit,
+    pub selected_by: Selection,
+    /// Select from the other end, e.g. least instead of most recently played.
+    pub reversed: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum LimitUnit {
+    Minutes,
+    Megabytes,
+    Items,
+    Hours,
+    Gigabytes,
+    Unknown(u8),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum Selection {
+    Random,
+    Name,
+    Album,
+    Artist,
+    Genre,
+    HighestRating,
+    LowestRating,
+    MostRecentlyPlayed,
+    MostOftenPlayed,
+    MostRecentlyAdded,
+    Unknown(u8),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum Conjunction {
+    All,
+    Any,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Criteria {
+    pub conjunction: Conjunction,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Rule {
+    Condition {
+        field: Field,
+        operator: Operator,
+        negated: bool,
+        value: RuleValue,
+    },
+    Group(Criteria),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum Operator {
+    Is,
+    Contains,
+    BeginsWith,
+    EndsWith,
+    GreaterThan,
+    LessThan,
+    InRange,
+    InTheLast,
+    /// Bitwise match, used for media kind and similar flags.
+    HasFlags,
+    Unknown(u32),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum RuleValue {
+    String(String),
+    Integer {
+        from: i64,
+        to: i64,
+    },
+    Date {
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    },
+    Duration {
+        amount: i64,
+        unit_seconds: i64,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum Field {
+    Name,
+    Album,
+    Artist,
+    BitRate,
+    SampleRate,
+    Year,
+    Genre,
+    Kind,
+    DateModified,
+    TrackNumber,
+    Size,
+    Time,
+    Comments,
+    DateAdded,
+    Composer,
+    PlayCount,
+    LastPlayed,
+    DiscNumber,
+    Rating,
+    Compilation,
+    Bpm,
+    Grouping,
+    Playlist,
+    Purchased,
+    Description,
+    MediaKind,
+    SkipCount,
+    LastSkipped,
+    AlbumArtist,
+    SortName,
+    SortAlbum,
+    SortArtist,
+    SortAlbumArtist,
+    SortComposer,
+    AlbumRating,
+    Location,
+    Unknown(u32),
+}
+
+impl Field {
+    fn from_id(id: u32) -> Self {
+        match id {
+            0x02 => Field::Name,
+            0x03 => Field::Album,
+            0x04 => Field::Artist,
+            0x05 => Field::BitRate,
+            0x06 => Field::SampleRate,
+            0x07 => Field::Year,
+            0x08 => Field::Genre,
+            0x09 => Field::Kind,
+            0x0a => Field::DateModified,
+            0x0b => Field::TrackNumber,
+            0x0c => Field::Size,
+            0x0d => Field::Time,
+            0x0e => Field::Comments,
+            0x10 => Field::DateAdded,
+            0x12 => Field::Composer,
+            0x16 => Field::PlayCount,
+            0x17 => Field::LastPlayed,
+            0x18 => Field::DiscNumber,
+            0x19 => Field::Rating,
+            0x1f => Field::Compilation,
+            0x23 => Field::Bpm,
+            0x27 => Field::Grouping,
+            0x28 => Field::Playlist,
+            0x29 => Field::Purchased,
+            0x36 => Field::Description,
+            0x3c => Field::MediaKind,
+            0x44 => Field::SkipCount,
+            0x45 => Field::LastSkipped,
+            0x47 => Field::AlbumArtist,
+            0x4e => Field::SortName,
+            0x4f => Field::SortAlbum,
+            0x50 => Field::SortArtist,
+            0x51 => Field::SortAlbumArtist,
+            0x52 => Field::SortComposer,
+            0x5a => Field::AlbumRating,
+            0x85 => Field::Location,
+            id => Field::Unknown(id),
+        }
+    }
+
+    fn is_date(&self) -> bool {
+        matches!(
+            self,
+            Field::DateModified | Field::DateAdded | Field::LastPlayed | Field::LastSkipped
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SmartPlaylistError {
+    BadMagic,
+    Truncated { offset: usize },
+    /// A date or duration beyond what can be represented.
+    OutOfRange { value: i64 },
+}
+
+impl fmt::Display for SmartPlaylistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmartPlaylistError::BadMagic => write!(f, "Smart Criteria does not start with SLst"),
+            SmartPlaylistError::Truncated { offset } => {
+                write!(f, "Smart playlist data ends unexpectedly at byte {offset}")
+            }
+            SmartPlaylistError::OutOfRange { value } => {
+                write!(f, "Smart playlist value {value} is out of range")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SmartPlaylistError {}
+
+impl Playlist {
+    /// Decodes `smart_info` and `smart_criteria`, `None` for regular playlists.
+    pub fn smart_playlist(&self) -> Result<Option<SmartPlaylist>, SmartPlaylistError> {
+        match (&self.smart_info, &self.smart_criteria) {
+            (Some(info), Some(criteria)) => {
+                SmartPlaylist::decode(info, criteria).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl SmartPlaylist {
+    pub fn decode(info: &[u8], criteria: &[u8]) -> Result<Self, SmartPlaylistError> {
+        if info.len() < INFO_LENGTH {
+            return Err(SmartPlaylistError::Truncated { offset: info.len() });
+        }
+
+        let limit = match info[2] {
+            0 => None,
+            _ => Some(Limit {
+                amount: read_u32(info, 8)?,
+                unit: match info[3] {
+                    0x01 => LimitUnit::Minutes,
+                    0x02 => LimitUnit::Megabytes,
+                    0x03 => LimitUnit::Items,
+                    0x04 => LimitUnit::Hours,
+                    0x05 => LimitUnit::Gigabytes,
+                    unit => LimitUnit::Unknown(unit),
+                },
+                selected_by: match info[7] {
+                    0x02 => Selection::Random,
+                    0x05 => Selection::Name,
+                    0x06 => Selection::Album,
+                    0x07 => Selection::Artist,
+                    0x09 => Selection::Genre,
+                    0x1c => Selection::HighestRating,
+                    0x01 => Selection::LowestRating,
+                    0x1a => Selection::MostRecentlyPlayed,
+                    0x19 => Selection::MostOftenPlayed,
+                    0x15 => Selection::MostRecentlyAdded,
+                    selection => Selection::Unknown(selection),
+                },
+                reversed: info[13] != 0,
+            }),
+        };
+
+        Ok(SmartPlaylist {
+            live_updating: info[0] != 0,
+            match_rules: info[1] != 0,
+            limit,
+            criteria: Criteria::decode(criteria)?,
+        })
+    }
+
+    /// Whether `track` satisfies the rules of this playlist. Limits are not applied.
+    pub fn matches(&self, track: &Track) -> bool {
+        !self.match_rules || self.criteria.matches(track)
+    }
+}
+
+impl Criteria {
+    pub fn decode(data: &[u8]) -> Result<Self, SmartPlaylistError> {
+        if !data.starts_with(SLST_MAGIC) {
+            return Err(SmartPlaylistError::BadMagic);
+        }
+        let count = read_u32(data, 8)?;
+        let conjunction = match read_u32(data, 12)? {
+            1 => Conjunction::Any,
+            _ => Conjunction::All,
+        };
+
+        let mut rules = Vec::new();
+        let mut offset = HEADER_LENGTH;
+        for _ in 0..count {
+            let field_id = read_u32(data, offset)?;
+            let operator = read_u32(data, offset + 4)?;
+            let length = read_u32(data, offset + RULE_HEADER_LENGTH - 4)? as usize;
+            let start = offset + RULE_HEADER_LENGTH;
+            let end = start
+                .checked_add(length)
+                .ok_or(SmartPlaylistError::Truncated { offset: start })?;
+            let value = data
+                .get(start..end)
+                .ok_or(SmartPlaylistError::Truncated { offset: start })?;
+
+            let rule = match value.starts_with(SLST_MAGIC) {
+                true => Rule::Group(Criteria::decode(value)?),
+                false => decode_condition(Field::from_id(field_id), operator, value)?,
+            };
+            rules.push(rule);
+            offset = end;
+        }
+
+        Ok(Criteria { conjunction, rules })
+    }
+
+    /// Whether `track` satisfies these rules. Rules on fields this crate does not track, like
+    /// media kind or playlist membership, are ignored.
+    pub fn matches(&self, track: &Track) -> bool {
+        self.evaluate(track, Utc::now().timestamp()).unwrap_or(true)
+    }
+
+    fn evaluate(&self, track: &Track, now: i64) -> Option<bool> {
+        let results = self.rules.iter().filter_map(|rule| match rule {
+            Rule::Group(criteria) => criteria.evaluate(track, now),
+            Rule::Condition {
+                field,
+                operator,
+                negated,
+                value,
+            } => evaluate_condition(track, *field, *operator, value, now)
+                .map(|matched| matched != *negated),
+        });
+        let results: Vec<bool> = results.collect();
+        match (results.is_empty(), self.conjunction) {
+            (true, _) => None,
+            (false, Conjunction::All) => Some(results.iter().all(|matched| *matched)),
+            (false, Conjunction::Any) => Some(results.iter().any(|matched| *matched)),
+        }
+    }
+}
+
+fn decode_condition(field: Field, operator: u32, value: &[u8]) -> Result<Rule, SmartPlaylistError> {
+    let negated = operator & NEGATED_FLAG != 0;
+    let value_operator = match operator & 0x00ff_ffff {
+        0x01 => Operator::Is,
+        0x02 => Operator::Contains,
+        0x04 => Operator::BeginsWith,
+        0x08 => Operator::EndsWith,
+        0x10 => Operator::GreaterThan,
+        0x40 => Operator::LessThan,
+        0x100 => Operator::InRange,
+        0x200 => Operator::InTheLast,
+        0x400 => Operator::HasFlags,
+        _ => Operator::Unknown(operator),
+    };
+
+    let value = if operator & STRING_FLAG != 0 {
+        let units: Vec<u16> = value
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        RuleValue::String(String::from_utf16_lossy(&units))
+    } else if value_operator == Operator::InTheLast {
+        // Stored negated, counting back from now
+        let stored = read_i64(value, 8)?;
+        let amount = stored
+            .checked_neg()
+            .ok_or(SmartPlaylistError::OutOfRange { value: stored })?;
+        let unit_seconds = read_i64(value, 16)?;
+        if amount.checked_mul(unit_seconds).is_none() {
+            return Err(SmartPlaylistError::OutOfRange { value: amount });
+        }
+        RuleValue::Duration {
+            amount,
+            unit_seconds,
+        }
+    } else {
+        let from = read_i64(value, 0)?;
+        let to = match value_operator {
+            Operator::InRange => read_i64(value, 24)?,
+            _ => from,
+        };
+        let date = |seconds| {
+            from_mac_seconds(seconds).ok_or(SmartPlaylistError::OutOfRange { value: seconds })
+        };
+        match field.is_date() {
+            true => RuleValue::Date {
+                from: date(from)?,
+                to: date(to)?,
+            },
+            false => RuleValue::Integer { from, to },
+        }
+    };
+
+    Ok(Rule::Condition {
+        field,
+        operator: value_operator,
+        negated,
+        value,
+    })
+}
+
+fn evaluate_condition(
+    track: &Track,
+    field: Field,
+    operator: Operator,
+    value: &RuleValue,
+    now: i64,
+) -> Option<bool> {
+    match value {
+        RuleValue::String(expected) => {
+            let actual = track_string(track, field)?.to_lowercase();
+            let expected = expected.to_lowercase();
+            match operator {
+                Operator::Is => Some(actual == expected),
+                Operator::Contains => Some(actual.contains(&expected)),
+                Operator::BeginsWith => Some(actual.starts_with(&expected)),
+                Operator::EndsWith => Some(actual.ends_with(&expected)),
+                _ => None,
+            }
+        }
+        RuleValue::Integer { from, to } => {
+            let actual = track_integer(track, field)?;
+            compare(actual, operator, *from, *to)
+        }
+        RuleValue::Date { from, to } => {
+            let actual = track_date(track, field)?;
+            let timestamp = |date| Utc.from_utc_datetime(date).timestamp();
+            compare(actual, operator, timestamp(from), timestamp(to))
+        }
+        RuleValue::Duration {
+            amount,
+            unit_seconds,
+        } => {
+            let actual = track_date(track, field)?;
+            let since = now.checked_sub(amount.checked_mul(*unit_seconds)?)?;
+            Some(actual >= since)
+        }
+    }
+}
+
+fn compare(actual: i64, operator: Operator, from: i64, to: i64) -> Option<bool> {
+    match operator {
+        Operator::Is => Some(actual == from),
+        Operator::GreaterThan => Some(actual > from),
+        Operator::LessThan => Some(actual < from),
+        Operator::InRange => Some(from <= actual && actual <= to),
+        Operator::HasFlags => Some(actual & from != 0),
+        _ => None,
+    }
+}
+
+fn track_string(track: &Track, field: Field) -> Option<&str> {
+    let value = match field {
+        Field::Name => &track.name,
+        Field::Album => &track.album,
+        Field::Artist => &track.artist,
+        Field::AlbumArtist => &track.album_artist,
+        Field::Genre => &track.genre,
+        Field::Kind => &track.kind,
+        Field::Comments => &track.comments,
+        Field::Grouping => &track.grouping,
+        Field::Composer => &track.composer,
+        Field::SortName => &track.sort_name,
+        Field::SortAlbum => &track.sort_album,
+        Field::SortArtist => &track.sort_artist,
+        Field::SortAlbumArtist => &track.sort_album_artist,
+        Field::SortComposer => &track.sort_composer,
+        Field::Location => &track.location,
+        _ => return None,
+    };
+    // Missing text matches like an empty string, as in iTunes
+    Some(value.as_deref().unwrap_or_default())
+}
+
+fn track_integer(track: &Track, field: Field) -> Option<i64> {
+    let value = match field {
+        Field::BitRate => track.bit_rate,
+        Field::SampleRate => track.sample_rate,
+        Field::Year => track.year,
+        Field::TrackNumber => track.track_number,
+        Field::Size => track.size,
+        Field::Time => track.total_time,
+        Field::PlayCount => track.play_count,
+        Field::DiscNumber => track.disc_number,
+        Field::Rating => track.rating,
+        Field::Bpm => track.bpm,
+        Field::SkipCount => track.skip_count,
+        Field::AlbumRating => track.album_rating,
+        Field::Compilation => Some(track.compilation.unwrap_or_default() as i64),
+        Field::Purchased => Some(track.purchased.unwrap_or_default() as i64),
+        _ => return None,
+    };
+    Some(value.unwrap_or_default())
+}
+
+fn track_date(track: &Track, field: Field) -> Option<i64> {
+    let value = match field {
+        Field::DateModified => &track.date_modified,
+        Field::DateAdded => &track.date_added,
+        Field::LastPlayed => &track.play_date_utc,
+        Field::LastSkipped => &track.skip_date,
+        _ => return None,
+    };
+    // Never played or skipped compares as the earliest possible date
+    Some(value.map_or(i64::MIN / 2, |date| date.timestamp()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, SmartPlaylistError> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(SmartPlaylistError::Truncated { offset })?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_i64(data: &[u8], offset: usize) -> Result<i64, SmartPlaylistError> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or(SmartPlaylistError::Truncated { offset })?;
+    Ok(i64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    // Smart Info and Smart Criteria from the `Playlist` documentation comment
+    const INFO: &str = "AQEAAwAAAAIAAAAZAAAAAAAAAAcAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+        AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+        AAAAAA==";
+    const CRITERIA: &str =
+        "U0xzdAABAAEAAAADAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+        AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+        AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAADwAAAQAAAAAAAAAAAAAAAAAAAAAAAAA
+        AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABEAAAAAAAQIbEAAAAAAAAAAAAAAAAAAAAB
+        AAAAAAAQIbEAAAAAAAAAAAAAAAAAAAABAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA8AgAEAAAA
+        AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAARAAAAAAAIIAE
+        AAAAAAAAAAAAAAAAAAAAAQAAAAAAIIAEAAAAAAAAAAAAAAAAAAAAAQAAAAAAAAAAAAAAAAAA
+        AAAAAAAAAAAAhQAABAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+        AAAAAAAAAEQAAAAAAAAAAQAAAAAAAAAAAAAAAAAAAAEAAAAAAAAAAQAAAAAAAAAAAAAAAAAA
+        AAEAAAAAAAAAAAAAAAAAAAAAAAAAAA==";
+
+    fn decode_base64(data: &str) -> Vec<u8> {
+        let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+        STANDARD.decode(data).unwrap()
+    }
+
+    #[test]
+    fn decodes_documented_blobs() {
+        let playlist = Playlist {
+            smart_info: Some(decode_base64(INFO)),
+            smart_criteria: Some(decode_base64(CRITERIA)),
+            ..Default::default()
+        };
+
+        let smart = playlist.smart_playlist().unwrap().unwrap();
+
+        assert!(smart.live_updating);
+        assert!(smart.match_rules);
+        assert_eq!(smart.limit, None);
+        assert_eq!(smart.criteria.conjunction, Conjunction::All);
+        assert_eq!(smart.criteria.rules.len(), 3);
+        assert_eq!(
+            smart.criteria.rules[1],
+            Rule::Condition {
+                field: Field::MediaKind,
+                operator: Operator::HasFlags,
+                negated: true,
+                value: RuleValue::Integer {
+                    from: 0x20_8004,
+                    to: 0x20_8004
+                },
+            }
+        );
+        assert_eq!(
+            smart.criteria.rules[2],
+            Rule::Condition {
+                field: Field::Location,
+                operator: Operator::HasFlags,
+                negated: false,
+                value: RuleValue::Integer { from: 1, to: 1 },
+            }
+        );
+    }
+
+    #[test]
+    fn evaluates_string_and_integer_rules() {
+        let genre = Criteria {
+            conjunction: Conjunction::All,
+            rules: vec![Rule::Condition {
+                field: Field::Genre,
+                operator: Operator::Contains,
+                negated: false,
+                value: RuleValue::String("jazz".to_string()),
+            }],
+        };
+        let bpm = Criteria {
+            conjunction: Conjunction::All,
+            rules: vec![Rule::Condition {
+                field: Field::Bpm,
+                operator: Operator::InRange,
+                negated: false,
+                value: RuleValue::Integer { from: 120, to: 130 },
+            }],
+        };
+
+        let track = Track {
+            genre: Some("Acid Jazz".to_string()),
+            bpm: Some(124),
+            ..Default::default()
+        };
+
+        assert!(genre.matches(&track));
+        assert!(bpm.matches(&track));
+        assert!(!bpm.matches(&Track {
+            bpm: Some(140),
+            ..Default::default()
+        }));
+        assert!(!genre.matches(&Track::default()));
+    }
+
+    #[test]
+    fn decodes_rule_values() {
+        let value: Vec<u8> = "jazz".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(
+            decode_condition(Field::Genre, STRING_FLAG | 0x02, &value),
+            Ok(Rule::Condition {
+                field: Field::Genre,
+                operator: Operator::Contains,
+                negated: false,
+                value: RuleValue::String("jazz".to_string()),
+            })
+        );
+
+        let mut bpm_range = vec![0; 68];
+        bpm_range[..8].copy_from_slice(&120i64.to_be_bytes());
+        bpm_range[24..32].copy_from_slice(&130i64.to_be_bytes());
+        assert_eq!(
+            decode_condition(Field::Bpm, NEGATED_FLAG | 0x100, &bpm_range),
+            Ok(Rule::Condition {
+                field: Field::Bpm,
+                operator: Operator::InRange,
+                negated: true,
+                value: RuleValue::Integer { from: 120, to: 130 },
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_dates_and_durations() {
+        let mut date = vec![0; 68];
+        date[..8].copy_from_slice(&i64::MAX.to_be_bytes());
+        assert_eq!(
+            decode_condition(Field::DateAdded, 0x10, &date),
+            Err(SmartPlaylistError::OutOfRange { value: i64::MAX })
+        );
+
+        let mut duration = vec![0; 68];
+        duration[8..16].copy_from_slice(&(-(1i64 << 40)).to_be_bytes());
+        duration[16..24].copy_from_slice(&(1i64 << 40).to_be_bytes());
+        assert_eq!(
+            decode_condition(Field::DateAdded, 0x200, &duration),
+            Err(SmartPlaylistError::OutOfRange { value: 1 << 40 })
+        );
+
+        duration[8..16].copy_from_slice(&i64::MIN.to_be_bytes());
+        assert_eq!(
+            decode_condition(Field::DateAdded, 0x200, &duration),
+            Err(SmartPlaylistError::OutOfRange { value: i64::MIN })
+        );
+    }
+
+    #[test]
+    fn rejects_rule_lengths_past_the_end() {
+        let mut data = vec![0; HEADER_LENGTH + RULE_HEADER_LENGTH];
+        data[..4].copy_from_slice(SLST_MAGIC);
+        data[8..12].copy_from_slice(&1u32.to_be_bytes());
+        data[HEADER_LENGTH + RULE_HEADER_LENGTH - 4..].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        assert_eq!(
+            Criteria::decode(&data),
+            Err(SmartPlaylistError::Truncated {
+                offset: HEADER_LENGTH + RULE_HEADER_LENGTH
+            })
+        );
+    }
+}
@@ -0,0 +1,487 @@
+//! Import of Traktor collections (`collection.nml`).
+//!
+//! ```xml
+//! <NML VERSION="19">
+//!   <COLLECTION ENTRIES="1">
+//!     <ENTRY TITLE="..." ARTIST="...">
+//!       <LOCATION DIR="/:Users/:lin/:Music/:" FILE="a.mp3" VOLUME="Macintosh HD"/>
+//!       <INFO PLAYTIME="230" RANKING="204" KEY="Fm" IMPORT_DATE="2021/3/14"/>
+//!       <TEMPO BPM="121.000000"/>
+//!       <CUE_V2 NAME="Intro" TYPE="0" START="120.0" LEN="0.0" HOTCUE="1"/>
+//!     </ENTRY>
+//!   </COLLECTION>
+//!   <PLAYLISTS>
+//!     <NODE TYPE="FOLDER" NAME="$ROOT"><SUBNODES>
+//!       <NODE TYPE="PLAYLIST" NAME="List"><PLAYLIST>
+//!         <ENTRY><PRIMARYKEY TYPE="TRACK" KEY="Macintosh HD/:Users/:lin/:Music/:a.mp3"/></ENTRY>
+//!       </PLAYLIST></NODE>
+//!     </SUBNODES></NODE>
+//!   </PLAYLISTS>
+//! </NML>
+//! ```
+//!
+//! Entries have no IDs, tracks are numbered in collection order and playlists refer to them by
+//! volume and path. Locations become `file://` URLs: drive letter volumes are kept, other volume
+//! names are dropped, so tracks on an external macOS volume need a [`crate::LocationRule`] from
+//! `file:///` to `file:///Volumes/<name>/`. Cue times are milliseconds, grid markers become
+//! tempo markers and the musical key is kept under Rekordbox's `Tonality`.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use xml::attribute::OwnedAttribute;
+use xml::reader::XmlEvent;
+
+use crate::attributes::{attribute, AttributeReader, NodeIds};
+use crate::error::Context;
+use crate::{
+    CuePoint, Element, Library, LibraryVisitor, ParseError, ParseReport, Playlist, TempoMarker,
+    Track,
+};
+
+/// `INFO` attributes with a dedicated `Track` field, everything else is kept in `extra`.
+const INFO_ATTRIBUTES: [&str; 11] = [
+    "BITRATE",
+    "GENRE",
+    "COMMENT",
+    "KEY",
+    "PLAYCOUNT",
+    "PLAYTIME",
+    "RANKING",
+    "IMPORT_DATE",
+    "RELEASE_DATE",
+    "FILESIZE",
+    "LABEL",
+];
+
+/// Traktor's cue types, `CUE_V2 TYPE`.
+const CUE_GRID: i64 = 4;
+const CUE_LOOP: i64 = 5;
+
+/// Whether the start of a document is a Traktor collection.
+pub fn is_traktor_nml(head: &[u8]) -> bool {
+    head.windows(b"<NML".len()).any(|window| window == b"<NML")
+}
+
+pub fn parse_traktor_nml(file_path: &str) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    visit_traktor_nml(file_path, false, &mut library)?;
+    Ok(library)
+}
+
+pub fn parse_traktor_nml_reader<R: Read>(reader: R) -> Result<Library, ParseError> {
+    let mut library = Library::default();
+    visit_traktor_nml_reader(reader, false, &mut library)?;
+    Ok(library)
+}
+
+/// Streams a Traktor collection to `visitor`, like [`crate::visit_itunes_xml`].
+pub fn visit_traktor_nml<V: LibraryVisitor>(
+    file_path: &str,
+    lenient: bool,
+    visitor: &mut V,
+) -> Result<ParseReport, ParseError> {
+    TraktorReader {
+        xml: AttributeReader::open(file_path, lenient)?,
+    }
+    .read(visitor)
+}
+
+pub fn visit_traktor_nml_reader<R: Read, V: LibraryVisitor>(
+    reader: R,
+    lenient: bool,
+    visitor: &mut V,
+) -> Result<ParseReport, ParseError> {
+    TraktorReader {
+        xml: AttributeReader::new(reader, None, lenient),
+    }
+    .read(visitor)
+}
+
+/// Collection entry being read.
+struct Entry {
+    track: Track,
+    /// Volume and path, as playlists refer to it.
+    key: Option<String>,
+    /// Tempo the grid markers of this entry use.
+    bpm: Option<f64>,
+}
+
+struct TraktorReader<R: Read> {
+    xml: AttributeReader<R>,
+}
+
+impl<R: Read> TraktorReader<R> {
+    fn read<V: LibraryVisitor>(mut self, visitor: &mut V) -> Result<ParseReport, ParseError> {
+        let mut in_collection = false;
+        // Collection entry being read, `None` inside a skipped one
+        let mut entry: Option<Option<Entry>> = None;
+        let mut next_track_id = 1;
+        let mut ids_by_key: HashMap<String, u64> = HashMap::new();
+        // Open playlist nodes, `None` for $ROOT
+        let mut nodes: Vec<Option<Playlist>> = Vec::new();
+        let mut next_playlist_id = 1;
+        let mut node_ids = NodeIds::new("TK");
+
+        loop {
+            let event = self.xml.next()?;
+            match event {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => match name.local_name.as_str() {
+                    "HEAD" => {
+                        for (key, name) in
+                            [("PROGRAM", "Product Name"), ("COMPANY", "Product Company")]
+                        {
+                            if let Some(value) = attribute(&attributes, key) {
+                                let value = Element::String(Some(value.to_string()));
+                                visitor.metadata(name.to_string(), value);
+                            }
+                        }
+                    }
+                    "COLLECTION" => in_collection = true,
+                    "ENTRY" if in_collection => {
+                        let id = next_track_id;
+                        next_track_id += 1;
+                        self.xml.context = Context::Track {
+                            id: Some(id),
+                            key: None,
+                        };
+                        let string = |key: &str| {
+                            attribute(&attributes, key)
+                                .filter(|value| !value.is_empty())
+                                .map(str::to_string)
+                        };
+                        let track = Track {
+                            id,
+                            name: string("TITLE"),
+                            artist: string("ARTIST"),
+                            ..Default::default()
+                        };
+                        entry = Some(Some(Entry {
+                            track,
+                            key: None,
+                            bpm: None,
+                        }));
+                    }
+                    element if in_collection => {
+                        let Some(Some(current)) = &mut entry else {
+                            continue;
+                        };
+                        match self.read_entry_element(current, element, &attributes) {
+                            Ok(()) => (),
+                            Err(err) => {
+                                self.xml.skip_track(err)?;
+                                entry = Some(None);
+                            }
+                        }
+                    }
+                    "NODE" => {
+                        let name = attribute(&attributes, "NAME").unwrap_or_default();
+                        if nodes.is_empty() && name == "$ROOT" {
+                            nodes.push(None);
+                            continue;
+                        }
+                        let id = next_playlist_id;
+                        next_playlist_id += 1;
+                        let path = nodes.iter().flatten().map(|node| node.name.as_str());
+                        let persistent_id = node_ids.next(path.chain([name]));
+                        nodes.push(Some(Playlist {
+                            id,
+                            name: name.to_string(),
+                            persistent_id,
+                            parent_persistent_id: nodes
+                                .iter()
+                                .rev()
+                                .flatten()
+                                .next()
+                                .map(|parent| parent.persistent_id.clone()),
+                            folder: Some(attribute(&attributes, "TYPE") == Some("FOLDER")),
+                            ..Default::default()
+                        }));
+                    }
+                    "PRIMARYKEY" => {
+                        let Some(Some(playlist)) = nodes.last_mut() else {
+                            continue;
+                        };
+                        let key = attribute(&attributes, "KEY").unwrap_or_default();
+                        match ids_by_key.get(key) {
+                            Some(id) => playlist.items.push(*id),
+                            None => {
+                                let reason = format!("Unknown track {:?}", key);
+                                self.xml.context = Context::Playlist {
+                                    name: Some(playlist.name.clone()),
+                                    key: Some("KEY".to_string()),
+                                };
+                                self.xml.warn(reason);
+                                self.xml.context = Context::Document;
+                            }
+                        }
+                    }
+                    _ => (),
+                },
+                XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                    "COLLECTION" => in_collection = false,
+                    "ENTRY" if in_collection => {
+                        if let Some(Some(Entry { track, key, .. })) = entry.take() {
+                            if let Some(key) = key {
+                                ids_by_key.insert(key, track.id);
+                            }
+                            visitor.track(track);
+                            visitor.progress(self.xml.progress());
+                        }
+                        self.xml.context = Context::Document;
+                    }
+                    "NODE" => {
+                        if let Some(Some(playlist)) = nodes.pop() {
+                            visitor.playlist(playlist);
+                            visitor.progress(self.xml.progress());
+                        }
+                    }
+                    _ => (),
+                },
+                XmlEvent::EndDocument => break,
+                _ => (),
+            }
+        }
+
+        Ok(self.xml.report)
+    }
+
+    /// Reads an element nested in a collection `ENTRY` into `entry`.
+    fn read_entry_element(
+        &mut self,
+        entry: &mut Entry,
+        element: &str,
+        attributes: &[OwnedAttribute],
+    ) -> Result<(), ParseError> {
+        let string = |key: &str| {
+            attribute(attributes, key)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let track = &mut entry.track;
+        match element {
+            "LOCATION" => {
+                let volume = attribute(attributes, "VOLUME").unwrap_or_default();
+                let dir = attribute(attributes, "DIR").unwrap_or_default();
+                let file = attribute(attributes, "FILE").unwrap_or_default();
+                track.location = Some(file_url(volume, dir, file));
+                entry.key = Some(format!("{}{}{}", volume, dir, file));
+            }
+            "ALBUM" => {
+                track.album = string("TITLE");
+                track.track_number = self.xml.integer(attributes, "TRACK")?;
+                track.track_count = self.xml.integer(attributes, "OF_TRACKS")?;
+            }
+            "INFO" => {
+                track.genre = string("GENRE");
+                track.comments = string("COMMENT");
+                track.play_count = self.xml.integer(attributes, "PLAYCOUNT")?;
+                track.total_time = self.xml.scaled_integer(attributes, "PLAYTIME", 1000)?;
+                track.bit_rate = self
+                    .xml
+                    .integer(attributes, "BITRATE")?
+                    .map(|bits| bits / 1000);
+                track.size = self.xml.scaled_integer(attributes, "FILESIZE", 1024)?;
+                track.rating = self
+                    .xml
+                    .scaled_integer(attributes, "RANKING", 100)?
+                    .filter(|ranking| *ranking != 0)
+                    .map(|ranking| ranking / 255);
+                // Dates are written `2021/3/14`
+                track.date_added = self.xml.date(attributes, "IMPORT_DATE", "%Y/%m/%d")?;
+                track.year = self.year(attributes, "RELEASE_DATE")?;
+                // Under Rekordbox's names, so a Rekordbox export carries them along
+                for (key, name) in [("KEY", "Tonality"), ("LABEL", "Label")] {
+                    if let Some(value) = string(key) {
+                        track
+                            .extra
+                            .insert(name.to_string(), Element::String(Some(value)));
+                    }
+                }
+                for attribute in attributes {
+                    let key = attribute.name.local_name.as_str();
+                    if !INFO_ATTRIBUTES.contains(&key) && !attribute.value.is_empty() {
+                        let value = Element::String(Some(attribute.value.clone()));
+                        track.extra.insert(key.to_string(), value);
+                    }
+                }
+            }
+            "TEMPO" => {
+                entry.bpm = self.xml.real(attributes, "BPM")?;
+                track.bpm = entry.bpm.map(|bpm| bpm.round() as i64);
+            }
+            "MUSICAL_KEY" => {
+                if let Some(value) = self.xml.integer(attributes, "VALUE")? {
+                    track
+                        .extra
+                        .insert("MUSICAL_KEY".to_string(), Element::Integer(value));
+                }
+            }
+            "CUE_V2" => {
+                let kind = self.xml.integer(attributes, "TYPE")?.unwrap_or_default();
+                let start = self.xml.real(attributes, "START")?.unwrap_or_default() / 1000.0;
+                if kind == CUE_GRID {
+                    track.tempo_markers.push(TempoMarker {
+                        start,
+                        bpm: entry.bpm.unwrap_or_default(),
+                        meter: "4/4".to_string(),
+                        beat: 1,
+                    });
+                    return Ok(());
+                }
+                let length = self.xml.real(attributes, "LEN")?.unwrap_or_default() / 1000.0;
+                track.cue_points.push(CuePoint {
+                    // Traktor shows unnamed cues as "n.n."
+                    name: string("NAME")
+                        .filter(|name| name != "n.n.")
+                        .unwrap_or_default(),
+                    kind: match kind {
+                        CUE_LOOP => 4,
+                        kind => kind,
+                    },
+                    start,
+                    end: (kind == CUE_LOOP).then_some(start + length),
+                    number: self.xml.integer(attributes, "HOTCUE")?.unwrap_or(-1),
+                    color: attribute(attributes, "COLOR").and_then(hex_color),
+                });
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Release dates with only a year known are written `1986/0/0`.
+    fn year(
+        &mut self,
+        attributes: &[OwnedAttribute],
+        key: &str,
+    ) -> Result<Option<i64>, ParseError> {
+        match attribute(attributes, key).filter(|value| !value.is_empty()) {
+            None => Ok(None),
+            Some(value) => match value.trim().split('/').next().unwrap_or_default().parse() {
+                Ok(year) => Ok(Some(year).filter(|year| *year != 0)),
+                Err(_) => Err(ParseError::BadDate {
+                    value: value.to_string(),
+                    at: self.xml.location_at(key),
+                }),
+            },
+        }
+    }
+}
+
+/// Builds a percent-encoded `file://` URL from Traktor's `/:`-separated directory.
+fn file_url(volume: &str, dir: &str, file: &str) -> String {
+    let mut path = format!("{}{}", dir.replace("/:", "/"), file);
+    if volume.len() == 2 && volume.ends_with(':') {
+        path = format!("/{}{}", volume, path);
+    }
+    let mut url = "file://".to_string();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => url.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' => {
+                url.push(byte as char)
+            }
+            b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' => url.push(byte as char),
+            byte => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+/// Parses `#RRGGBB`.
+fn hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+    let channel = |at: usize| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_collection_and_playlists() {
+        let library = parse_traktor_nml("tests/fixtures/collection.nml").unwrap();
+
+        assert_eq!(library.tracks.len(), 3);
+        let track = &library.tracks[&1];
+        assert_eq!(track.name.as_deref(), Some("Bizarre Love Triangle"));
+        assert_eq!(
+            track.location.as_deref(),
+            Some("file:///Users/lin/Music/Bizarre%20Love%20Triangle.m4a")
+        );
+        assert_eq!(track.album.as_deref(), Some("Brotherhood"));
+        assert_eq!(track.track_number, Some(11));
+        assert_eq!(track.total_time, Some(230_000));
+        assert_eq!(track.bit_rate, Some(256));
+        assert_eq!(track.bpm, Some(121));
+        assert_eq!(track.rating, Some(80));
+        assert_eq!(track.year, Some(1986));
+        assert_eq!(track.date_added.unwrap().timestamp(), 1_615_680_000);
+        assert_eq!(
+            track.extra.get("Tonality"),
+            Some(&Element::String(Some("Fm".to_string())))
+        );
+        assert_eq!(track.tempo_markers.len(), 1);
+        assert_eq!(track.tempo_markers[0].start, 0.12);
+        assert_eq!(track.tempo_markers[0].bpm, 121.0);
+        assert_eq!(track.cue_points.len(), 2);
+        assert_eq!(track.cue_points[0].name, "Intro");
+        assert_eq!(track.cue_points[0].number, 1);
+        assert_eq!(track.cue_points[0].color, Some([40, 226, 20]));
+        assert_eq!(track.cue_points[1].kind, 4);
+        assert_eq!(track.cue_points[1].end, Some(38.055));
+        assert_eq!(
+            library.tracks[&2].location.as_deref(),
+            Some("file:///Music/12%22/Blue%20Monday.mp3")
+        );
+        assert_eq!(
+            library.tracks[&3].location.as_deref(),
+            Some("file:///C:/Users/lin/Music/Regret.wav")
+        );
+
+        let tree = library.playlist_tree();
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].name, "Gigs");
+        assert!(tree[0].folder);
+        assert_eq!(tree[0].children[0].name, "Club X");
+        assert!(tree[0].children[0].persistent_id.starts_with("TK"));
+        assert_eq!(
+            library.playlists[&tree[0].children[0].id].items,
+            vec![2, 1, 2]
+        );
+        assert_eq!(library.playlists[&tree[1].id].items, vec![3]);
+        assert_eq!(
+            library.metadata.get("Product Name"),
+            Some(&Element::String(Some("Traktor".to_string())))
+        );
+    }
+
+    #[test]
+    fn detects_format() {
+        let traktor = std::fs::read("tests/fixtures/collection.nml").unwrap();
+        let rekordbox = std::fs::read("tests/fixtures/rekordbox.xml").unwrap();
+
+        assert!(is_traktor_nml(&traktor[..256]));
+        assert!(!is_traktor_nml(&rekordbox[..256]));
+    }
+
+    #[test]
+    fn skips_malformed_entries_when_lenient() {
+        let nml = std::fs::read_to_string("tests/fixtures/collection.nml")
+            .unwrap()
+            .replace("PLAYTIME=\"449\"", "PLAYTIME=\"long\"");
+        let mut library = Library::default();
+
+        let report = visit_traktor_nml_reader(nml.as_bytes(), true, &mut library).unwrap();
+
+        assert_eq!(report.skipped_tracks, 1);
+        assert_eq!(report.diagnostics[0].track_id, Some(2));
+        assert_eq!(report.diagnostics[0].key.as_deref(), Some("PLAYTIME"));
+        assert_eq!(library.tracks.len(), 2);
+        assert!(parse_traktor_nml_reader(nml.as_bytes()).is_err());
+    }
+}
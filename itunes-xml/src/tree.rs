@@ -0,0 +1,156 @@
+//! Playlist folders, assembled from each playlist's `Parent Persistent ID`.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Library, Playlist};
+
+/// A playlist or folder together with the playlists filed under it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct PlaylistNode {
+    pub id: u64,
+    pub persistent_id: String,
+    pub name: String,
+    pub folder: bool,
+    /// Tracks in playlist order without duplicates, for folders the union of everything below.
+    pub track_ids: Vec<u64>,
+    /// Ordered by playlist ID, which follows the order of the export.
+    pub children: Vec<PlaylistNode>,
+}
+
+impl PlaylistNode {
+    /// Looks up a node by persistent ID in this subtree.
+    pub fn find(&self, persistent_id: &str) -> Option<&PlaylistNode> {
+        if self.persistent_id == persistent_id {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find(persistent_id))
+    }
+}
+
+/// Builds the folder hierarchy. Playlists whose parent is missing end up at the top level,
+/// playlists caught in a parent cycle are left out.
+pub fn playlist_tree<'a>(playlists: impl IntoIterator<Item = &'a Playlist>) -> Vec<PlaylistNode> {
+    let mut playlists: Vec<&Playlist> = playlists.into_iter().collect();
+    playlists.sort_by_key(|playlist| playlist.id);
+
+    let known: HashSet<&str> = playlists
+        .iter()
+        .map(|playlist| playlist.persistent_id.as_str())
+        .collect();
+    let mut children: HashMap<&str, Vec<&Playlist>> = HashMap::new();
+    let mut roots = Vec::new();
+    for playlist in playlists {
+        match playlist.parent_persistent_id.as_deref() {
+            Some(parent) if known.contains(parent) => {
+                children.entry(parent).or_default().push(playlist)
+            }
+            _ => roots.push(playlist),
+        }
+    }
+
+    let mut visited = HashSet::new();
+    roots
+        .into_iter()
+        .filter_map(|playlist| node(playlist, &children, &mut visited))
+        .collect()
+}
+
+fn node<'a>(
+    playlist: &'a Playlist,
+    children: &HashMap<&str, Vec<&'a Playlist>>,
+    visited: &mut HashSet<&'a str>,
+) -> Option<PlaylistNode> {
+    if !visited.insert(&playlist.persistent_id) {
+        return None;
+    }
+
+    let child_nodes: Vec<PlaylistNode> = children
+        .get(playlist.persistent_id.as_str())
+        .into_iter()
+        .flatten()
+        .filter_map(|child| node(child, children, visited))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let track_ids = playlist
+        .items
+        .iter()
+        .chain(child_nodes.iter().flat_map(|child| &child.track_ids))
+        .filter(|id| seen.insert(**id))
+        .copied()
+        .collect();
+
+    Some(PlaylistNode {
+        id: playlist.id,
+        persistent_id: playlist.persistent_id.clone(),
+        name: playlist.name.clone(),
+        folder: playlist.folder.unwrap_or(false),
+        track_ids,
+        children: child_nodes,
+    })
+}
+
+impl Library {
+    pub fn playlist_tree(&self) -> Vec<PlaylistNode> {
+        playlist_tree(self.playlists.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(id: u64, name: &str, parent: Option<&str>, items: &[u64]) -> Playlist {
+        Playlist {
+            id,
+            name: name.to_string(),
+            persistent_id: format!("P{id}"),
+            parent_persistent_id: parent.map(str::to_string),
+            folder: Some(items.is_empty()),
+            items: items.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nests_folders_and_unites_tracks() {
+        let playlists = [
+            playlist(4, "Club X", Some("P2"), &[7, 8, 7]),
+            playlist(1, "Gigs", None, &[]),
+            playlist(2, "2023", Some("P1"), &[]),
+            playlist(3, "Warm-up", Some("P2"), &[9, 8]),
+            playlist(5, "Orphan", Some("P404"), &[1]),
+        ];
+
+        let tree = playlist_tree(&playlists);
+
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].name, "Gigs");
+        assert_eq!(tree[1].name, "Orphan");
+        let year = &tree[0].children[0];
+        assert!(year.folder);
+        assert_eq!(year.children[0].name, "Warm-up");
+        assert_eq!(year.children[1].track_ids, vec![7, 8]);
+        assert_eq!(year.track_ids, vec![9, 8, 7]);
+        assert_eq!(tree[0].track_ids, vec![9, 8, 7]);
+        assert_eq!(tree[0].find("P4").map(|node| node.id), Some(4));
+    }
+
+    #[test]
+    fn drops_parent_cycles() {
+        let playlists = [
+            playlist(1, "A", Some("P2"), &[1]),
+            playlist(2, "B", Some("P1"), &[2]),
+            playlist(3, "C", None, &[3]),
+        ];
+
+        let tree = playlist_tree(&playlists);
+
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].name, "C");
+    }
+}
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Element, Library, Playlist, Track};
+
+/// How far into the source document the parser has got.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Progress {
+    pub bytes_read: u64,
+    pub total_bytes: Option<u64>,
+}
+
+impl Progress {
+    /// Percentage of the document read so far, if its size is known.
+    pub fn percent(&self) -> Option<f64> {
+        match self.total_bytes {
+            Some(0) | None => None,
+            Some(total) => Some((self.bytes_read.min(total) as f64 / total as f64) * 100.0),
+        }
+    }
+}
+
+/// Receives library contents as they are parsed, without the whole library being kept in memory.
+///
+/// Every method has an empty default implementation, so visitors only override what they need.
+pub trait LibraryVisitor {
+    fn metadata(&mut self, _key: String, _value: Element) {}
+
+    fn track(&mut self, _track: Track) {}
+
+    fn playlist(&mut self, _playlist: Playlist) {}
+
+    /// Called after every track and playlist.
+    fn progress(&mut self, _progress: Progress) {}
+}
+
+impl LibraryVisitor for Library {
+    fn metadata(&mut self, key: String, value: Element) {
+        self.metadata.insert(key, value);
+    }
+
+    fn track(&mut self, track: Track) {
+        self.tracks.insert(track.id, track);
+    }
+
+    fn playlist(&mut self, playlist: Playlist) {
+        self.playlists.insert(playlist.id, playlist);
+    }
+}
@@ -0,0 +1,374 @@
+use std::io::{self, Write};
+
+use xml::escape::escape_str_pcdata;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+
+use crate::dates::{format_date, to_mac_seconds};
+use crate::{Element, Library, Playlist, Track};
+
+const HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+"#;
+
+/// Metadata keys in the order iTunes writes them, any other keys follow alphabetically.
+const METADATA_ORDER: [&str; 8] = [
+    "Major Version",
+    "Minor Version",
+    "Date",
+    "Application Version",
+    "Features",
+    "Show Content Ratings",
+    "Music Folder",
+    "Library Persistent ID",
+];
+
+/// Base64 characters per line inside `<data>`, as in iTunes exports.
+const DATA_LINE_LENGTH: usize = 72;
+
+/// Writes `library` in the plist layout of an iTunes "Export Library" file. Fails with
+/// `InvalidInput` for container markers among the metadata or extra fields, which carry no
+/// contents to write.
+pub fn write_itunes_xml<W: Write>(library: &Library, writer: W) -> io::Result<()> {
+    let mut plist = PlistWriter {
+        out: writer,
+        indent: 0,
+    };
+    plist.out.write_all(HEADER.as_bytes())?;
+    plist.open("dict")?;
+
+    let mut metadata: Vec<_> = library.metadata.iter().collect();
+    metadata.sort_by_key(|(key, _)| {
+        let position = METADATA_ORDER.iter().position(|known| known == key);
+        (position.unwrap_or(METADATA_ORDER.len()), key.as_str())
+    });
+    for (key, value) in metadata {
+        plist.element(key, value)?;
+    }
+
+    plist.key("Tracks")?;
+    plist.open("dict")?;
+    let mut tracks: Vec<_> = library.tracks.values().collect();
+    tracks.sort_by_key(|track| track.id);
+    for track in tracks {
+        plist.key(&track.id.to_string())?;
+        plist.track(track)?;
+    }
+    plist.close("dict")?;
+
+    plist.key("Playlists")?;
+    plist.open("array")?;
+    let mut playlists: Vec<_> = library.playlists.values().collect();
+    playlists.sort_by_key(|playlist| playlist.id);
+    for playlist in playlists {
+        plist.playlist(playlist)?;
+    }
+    plist.close("array")?;
+
+    plist.close("dict")?;
+    plist.out.write_all(b"</plist>\n")?;
+    plist.out.flush()
+}
+
+pub fn to_itunes_xml_string(library: &Library) -> io::Result<String> {
+    let mut buffer = Vec::new();
+    write_itunes_xml(library, &mut buffer)?;
+    Ok(String::from_utf8(buffer).expect("Escaped plist is valid UTF-8"))
+}
+
+impl Track {
+    /// The fields that are set, as plist keys and values in the order iTunes writes them.
+    /// `Track ID` and the `extra` fields are not included.
+    pub fn fields(&self) -> Vec<(&'static str, Element)> {
+        let string =
+            |value: &Option<String>| value.clone().map(|value| Element::String(Some(value)));
+        let integer = |value: Option<i64>| value.map(Element::Integer);
+        let date = |value: &Option<DateTime<Utc>>| value.map(Element::Date);
+        let boolean = |value: Option<bool>| value.map(Element::Boolean);
+
+        let fields = [
+            ("Name", string(&self.name)),
+            ("Artist", string(&self.artist)),
+            ("Album Artist", string(&self.album_artist)),
+            ("Composer", string(&self.composer)),
+            ("Album", string(&self.album)),
+            ("Genre", string(&self.genre)),
+            ("Kind", string(&self.kind)),
+            ("Size", integer(self.size)),
+            ("Total Time", integer(self.total_time)),
+            ("Disc Number", integer(self.disc_number)),
+            ("Disc Count", integer(self.disc_count)),
+            ("Track Number", integer(self.track_number)),
+            ("Track Count", integer(self.track_count)),
+            ("Year", integer(self.year)),
+            ("BPM", integer(self.bpm)),
+            ("Date Modified", date(&self.date_modified)),
+            ("Date Added", date(&self.date_added)),
+            ("Bit Rate", integer(self.bit_rate)),
+            ("Sample Rate", integer(self.sample_rate)),
+            ("Comments", string(&self.comments)),
+            ("Equalizer", string(&self.equalizer)),
+            ("Play Count", integer(self.play_count)),
+            (
+                "Play Date",
+                integer(self.play_date.as_ref().map(to_mac_seconds)),
+            ),
+            ("Play Date UTC", date(&self.play_date_utc)),
+            ("Skip Count", integer(self.skip_count)),
+            ("Skip Date", date(&self.skip_date)),
+            ("Release Date", date(&self.release_date)),
+            ("Normalization", integer(self.normalization)),
+            ("Volume Adjustment", integer(self.volume_adjustment)),
+            ("Rating", integer(self.rating)),
+            ("Rating Computed", boolean(self.rating_computed)),
+            ("Album Rating", integer(self.album_rating)),
+            ("Album Rating Computed", boolean(self.album_rating_computed)),
+            ("Artwork Count", integer(self.artwork_count)),
+            ("Content Rating", string(&self.content_rating)),
+            ("Sort Album", string(&self.sort_album)),
+            ("Sort Album Artist", string(&self.sort_album_artist)),
+            ("Sort Artist", string(&self.sort_artist)),
+            ("Sort Composer", string(&self.sort_composer)),
+            ("Sort Name", string(&self.sort_name)),
+            ("Persistent ID", string(&self.persistent_id)),
+            ("Loved", boolean(self.loved)),
+            ("Disliked", boolean(self.disliked)),
+            ("Favorited", boolean(self.favorited)),
+            ("Matched", boolean(self.matched)),
+            ("Explicit", boolean(self.explicit)),
+            ("Compilation", boolean(self.compilation)),
+            ("Part Of Gapless Album", boolean(self.part_of_gapless_album)),
+            ("Movie", boolean(self.movie)),
+            ("Podcast", boolean(self.podcast)),
+            ("Unplayed", boolean(self.unplayed)),
+            ("Purchased", boolean(self.purchased)),
+            ("Music Video", boolean(self.music_video)),
+            ("Has Video", boolean(self.has_video)),
+            ("HD", boolean(self.hd)),
+            ("Track Type", string(&self.track_type)),
+            ("Location", string(&self.location)),
+            ("File Folder Count", integer(self.file_folder_count)),
+            ("Library Folder Count", integer(self.library_folder_count)),
+            ("Grouping", string(&self.grouping)),
+            ("Work", string(&self.work)),
+            ("Movement Name", string(&self.movement_name)),
+            ("Movement Number", integer(self.movement_number)),
+            ("Start Time", integer(self.start_time)),
+            ("Stop Time", integer(self.stop_time)),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect()
+    }
+}
+
+struct PlistWriter<W: Write> {
+    out: W,
+    indent: usize,
+}
+
+impl<W: Write> PlistWriter<W> {
+    fn track(&mut self, track: &Track) -> io::Result<()> {
+        self.open("dict")?;
+        self.integer("Track ID", Some(track.id as i64))?;
+        for (key, value) in track.fields() {
+            self.element(key, &value)?;
+        }
+
+        let mut extra: Vec<_> = track.extra.iter().collect();
+        extra.sort_by_key(|(key, _)| key.as_str());
+        for (key, value) in extra {
+            self.element(key, value)?;
+        }
+        self.close("dict")
+    }
+
+    fn playlist(&mut self, playlist: &Playlist) -> io::Result<()> {
+        self.open("dict")?;
+        self.value("Name", "string", &playlist.name)?;
+        self.string("Description", &playlist.description)?;
+        self.boolean("Master", playlist.master)?;
+        self.boolean("Visible", playlist.visible)?;
+        self.integer("Playlist ID", Some(playlist.id as i64))?;
+        self.value("Playlist Persistent ID", "string", &playlist.persistent_id)?;
+        self.string("Parent Persistent ID", &playlist.parent_persistent_id)?;
+        self.integer("Distinguished Kind", playlist.distinguished_kind)?;
+        self.boolean("Music", playlist.music)?;
+        self.boolean("Movies", playlist.movies)?;
+        self.boolean("TV Shows", playlist.tv_shows)?;
+        self.boolean("Podcasts", playlist.podcasts)?;
+        self.boolean("Audiobooks", playlist.audiobooks)?;
+        self.boolean("Folder", playlist.folder)?;
+        if playlist.all_items {
+            self.boolean("All Items", Some(true))?;
+        }
+        self.data("Smart Info", playlist.smart_info.as_deref())?;
+        self.data("Smart Criteria", playlist.smart_criteria.as_deref())?;
+
+        if !playlist.items.is_empty() {
+            self.key("Playlist Items")?;
+            self.open("array")?;
+            for id in &playlist.items {
+                self.open("dict")?;
+                self.integer("Track ID", Some(*id as i64))?;
+                self.close("dict")?;
+            }
+            self.close("array")?;
+        }
+        self.close("dict")
+    }
+
+    fn element(&mut self, key: &str, value: &Element) -> io::Result<()> {
+        match value {
+            Element::Boolean(b) => self.boolean(key, Some(*b)),
+            Element::Integer(i) => self.integer(key, Some(*i)),
+            Element::String(s) => self.string(key, s),
+            Element::Data(d) => self.data(key, Some(d)),
+            Element::Date(d) => self.value(key, "date", &format_date(d)),
+            // Containers are only markers, their contents are not kept in the metadata
+            Element::Plist | Element::Dict | Element::Array | Element::Key(_) => {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("No value to write for {:?}: {:?}", key, value),
+                ))
+            }
+        }
+    }
+
+    fn string(&mut self, key: &str, value: &Option<String>) -> io::Result<()> {
+        match value {
+            Some(value) => self.value(key, "string", value),
+            None => Ok(()),
+        }
+    }
+
+    fn integer(&mut self, key: &str, value: Option<i64>) -> io::Result<()> {
+        match value {
+            Some(value) => self.value(key, "integer", &value.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn data(&mut self, key: &str, value: Option<&[u8]>) -> io::Result<()> {
+        let Some(value) = value else {
+            return Ok(());
+        };
+        self.key(key)?;
+        self.write_indent()?;
+        writeln!(self.out, "<data>")?;
+        let encoded = BASE64.encode(value);
+        for line in encoded.as_bytes().chunks(DATA_LINE_LENGTH) {
+            self.write_indent()?;
+            self.out.write_all(line)?;
+            writeln!(self.out)?;
+        }
+        self.write_indent()?;
+        writeln!(self.out, "</data>")
+    }
+
+    fn boolean(&mut self, key: &str, value: Option<bool>) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.write_indent()?;
+                writeln!(
+                    self.out,
+                    "<key>{}</key><{}/>",
+                    escape_str_pcdata(key),
+                    value
+                )
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn value(&mut self, key: &str, tag: &str, value: &str) -> io::Result<()> {
+        self.write_indent()?;
+        writeln!(
+            self.out,
+            "<key>{}</key><{tag}>{}</{tag}>",
+            escape_str_pcdata(key),
+            escape_str_pcdata(value)
+        )
+    }
+
+    fn key(&mut self, key: &str) -> io::Result<()> {
+        self.write_indent()?;
+        writeln!(self.out, "<key>{}</key>", escape_str_pcdata(key))
+    }
+
+    fn open(&mut self, tag: &str) -> io::Result<()> {
+        self.write_indent()?;
+        self.indent += 1;
+        writeln!(self.out, "<{tag}>")
+    }
+
+    fn close(&mut self, tag: &str) -> io::Result<()> {
+        self.indent -= 1;
+        self.write_indent()?;
+        writeln!(self.out, "</{tag}>")
+    }
+
+    fn write_indent(&mut self) -> io::Result<()> {
+        for _ in 0..self.indent {
+            self.out.write_all(b"\t")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_itunes_xml, parse_itunes_xml_str};
+
+    #[test]
+    fn round_trips_fixture() {
+        let library = parse_itunes_xml("tests/fixtures/Playlist-_lin next party.xml").unwrap();
+
+        let xml = to_itunes_xml_string(&library).unwrap();
+        let reparsed = parse_itunes_xml_str(&xml).unwrap();
+
+        assert_eq!(reparsed, library);
+        assert_eq!(reparsed.tracks.len(), 54);
+    }
+
+    #[test]
+    fn escapes_text() {
+        let library = parse_itunes_xml("tests/fixtures/single-track.xml").unwrap();
+
+        let xml = to_itunes_xml_string(&library).unwrap();
+
+        assert!(xml.contains("Stephen Morris &amp; Bernard Sumner"));
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE plist"));
+        assert!(xml.contains("\t<key>Major Version</key><integer>1</integer>\n"));
+    }
+
+    #[test]
+    fn round_trips_data() {
+        let mut library = parse_itunes_xml("tests/fixtures/single-track.xml").unwrap();
+        let artwork: Vec<u8> = (0..=255).collect();
+        library
+            .metadata
+            .insert("Artwork".to_string(), Element::Data(artwork.clone()));
+
+        let xml = to_itunes_xml_string(&library).unwrap();
+        let reparsed = parse_itunes_xml_str(&xml).unwrap();
+
+        assert!(xml.contains("\t<key>Artwork</key>\n\t<data>\n\tAAECAwQF"));
+        assert_eq!(reparsed.metadata["Artwork"], Element::Data(artwork));
+    }
+
+    #[test]
+    fn rejects_container_markers() {
+        let mut library = parse_itunes_xml("tests/fixtures/single-track.xml").unwrap();
+        library.metadata.insert("Nested".to_string(), Element::Dict);
+
+        let err = to_itunes_xml_string(&library).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use itunes_xml::{Element, LibraryInfo, LibraryVisitor, Playlist, Progress, Track};
+use types::{ImportProgress, LibraryFormat};
+
+use crate::playlists::free_playlist_id;
+use crate::tracks::{
+    find_track, free_track_id, insert_track, keep_local_fields, load_source_tracks, update_track,
+};
+
+type ProgressCallback<'a> = Box<dyn FnMut(&ImportProgress) + 'a>;
+
+/// Sources of the tracks and playlists imported from library exports, one per format so each
+/// format's re-imports only replace its own rows.
+pub const ITUNES_SOURCE: &str = "itunes";
+pub const REKORDBOX_SOURCE: &str = "rekordbox";
+pub const TRAKTOR_SOURCE: &str = "traktor";
+/// Source of the tracks read by a folder scan.
+pub const FOLDER_SOURCE: &str = "folder";
+/// Source of the playlists made in the app, which no import replaces.
+pub const LOCAL_SOURCE: &str = "local";
+
+pub fn library_source(format: LibraryFormat) -> &'static str {
+    match format {
+        LibraryFormat::Itunes => ITUNES_SOURCE,
+        LibraryFormat::Rekordbox => REKORDBOX_SOURCE,
+        LibraryFormat::Traktor => TRAKTOR_SOURCE,
+    }
+}
+
+/// Merges tracks and inserts playlist items into the database as soon as the parser yields
+/// them. Locations are stored as exported, the remapping rules apply when they are used. A
+/// track already stored under the same persistent ID, or the same location when the source has none, keeps its ID. Smart
+/// playlists are filled by evaluating their rules against the stored tracks.
+pub struct LibraryImporter<'a> {
+    conn: &'a Connection,
+    /// Stored with every row, re-imports only merge with rows of the same source.
+    source: &'a str,
+    /// Tracks the parser yielded, including ones that failed to import.
+    parsed: usize,
+    pub imported: usize,
+    /// Tracks the database did not have yet.
+    pub added: usize,
+    /// Stored tracks whose metadata changed.
+    pub updated: usize,
+    /// Stored ID of every imported track by its ID in the source, for the playlist items.
+    ids: HashMap<u64, u64>,
+    /// Stored tracks of this source, loaded at the first smart playlist, which follows the
+    /// tracks.
+    stored: Option<Vec<Track>>,
+    pub playlists: usize,
+    pub metadata: HashMap<String, Element>,
+    pub error: Option<rusqlite::Error>,
+    last_percent: Option<u32>,
+    /// Called whenever the whole percentage read changes.
+    on_progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a> LibraryImporter<'a> {
+    pub fn new(conn: &'a Connection, source: &'a str) -> Self {
+        LibraryImporter {
+            conn,
+            source,
+            parsed: 0,
+            imported: 0,
+            added: 0,
+            updated: 0,
+            ids: HashMap::new(),
+            stored: None,
+            playlists: 0,
+            metadata: HashMap::new(),
+            error: None,
+            last_percent: None,
+            on_progress: None,
+        }
+    }
+
+    /// Reports progress to `callback` whenever the whole percentage read changes.
+    pub fn on_progress(mut self, callback: impl FnMut(&ImportProgress) + 'a) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Counts so far, for progress and the final summary.
+    pub fn report(&self) -> ImportProgress {
+        ImportProgress {
+            percent: self.last_percent.map(f64::from),
+            tracks_parsed: self.parsed,
+            tracks_imported: self.imported,
+            playlists_imported: self.playlists,
+        }
+    }
+}
+
+impl<'a> LibraryImporter<'a> {
+    /// Overwrites the stored track with the imported metadata, keeping its ID and the fields
+    /// the app owns.
+    fn merge(&mut self, stored: Track, track: Track) -> rusqlite::Result<u64> {
+        let track = Track {
+            id: stored.id,
+            ..track
+        };
+        let merged = keep_local_fields(track, &stored);
+        if merged != stored {
+            self.updated += 1;
+        }
+        // Also run for unchanged tracks, to clear their removed flag
+        update_track(self.conn, &merged)?;
+        Ok(merged.id)
+    }
+
+    /// Inserts a new track under its source ID, or the next free one when a stored track
+    /// already has it.
+    fn add(&mut self, track: Track) -> rusqlite::Result<u64> {
+        let id = free_track_id(self.conn, track.id)?;
+        insert_track(self.conn, &Track { id, ..track })?;
+        self.conn
+            .prepare_cached("UPDATE tracks SET source = ?2 WHERE id = ?1")?
+            .execute((id, self.source))?;
+        self.added += 1;
+        Ok(id)
+    }
+
+    /// Stored IDs of the tracks matching a smart playlist's rules, so ratings and play counts
+    /// edited in the app count. `None` for other playlists, and for smart playlists with a
+    /// limit or rules that don't decode, which keep their exported items.
+    fn smart_items(&mut self, playlist: &Playlist) -> rusqlite::Result<Option<Vec<u64>>> {
+        let smart = match playlist.smart_playlist() {
+            Ok(Some(smart)) if smart.limit.is_none() => smart,
+            _ => return Ok(None),
+        };
+        if self.stored.is_none() {
+            self.stored = Some(load_source_tracks(self.conn, self.source)?);
+        }
+        let stored = self.stored.as_deref().unwrap_or_default();
+        let items = stored
+            .iter()
+            .filter(|track| smart.matches(track))
+            .map(|track| track.id)
+            .collect();
+        Ok(Some(items))
+    }
+}
+
+impl<'a> LibraryVisitor for LibraryImporter<'a> {
+    fn metadata(&mut self, key: String, value: Element) {
+        self.metadata.insert(key, value);
+    }
+
+    fn track(&mut self, track: Track) {
+        self.parsed += 1;
+        // Keep parsing after a failed insert, the first error is reported once done
+        if self.error.is_some() {
+            return;
+        }
+
+        let source_id = track.id;
+        let result = match find_track(self.conn, &track, self.source) {
+            Ok(Some(stored)) => self.merge(stored, track),
+            Ok(None) => self.add(track),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(id) => {
+                self.ids.insert(source_id, id);
+                self.imported += 1;
+            }
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    fn playlist(&mut self, playlist: Playlist) {
+        if self.error.is_some() {
+            return;
+        }
+
+        // Playlists nest by persistent ID, the ID can move aside for one made in the app
+        let id = match free_playlist_id(self.conn, playlist.id) {
+            Ok(id) => id,
+            Err(err) => {
+                self.error = Some(err);
+                return;
+            }
+        };
+        let result = self
+            .conn
+            .prepare_cached(
+                "INSERT INTO playlists (
+                    id,
+                    persistent_id,
+                    parent_persistent_id,
+                    name,
+                    folder,
+                    source
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6
+                );",
+            )
+            .and_then(|mut statement| {
+                statement.execute((
+                    id,
+                    &playlist.persistent_id,
+                    &playlist.parent_persistent_id,
+                    &playlist.name,
+                    &playlist.folder,
+                    self.source,
+                ))
+            });
+        if let Err(err) = result {
+            self.error = Some(err);
+            return;
+        }
+
+        let items = match self.smart_items(&playlist) {
+            Ok(Some(items)) => items,
+            // Items of skipped tracks are dropped
+            Ok(None) => playlist
+                .items
+                .iter()
+                .filter_map(|id| self.ids.get(id))
+                .copied()
+                .collect(),
+            Err(err) => {
+                self.error = Some(err);
+                return;
+            }
+        };
+        for (position, track_id) in items.into_iter().enumerate() {
+            let result = self
+                .conn
+                .prepare_cached(
+                    "INSERT INTO playlist_items (
+                        playlist_id,
+                        position,
+                        track_id
+                    ) VALUES (
+                        ?1, ?2, ?3
+                    );",
+                )
+                .and_then(|mut statement| statement.execute((id, position, track_id)));
+            if let Err(err) = result {
+                self.error = Some(err);
+                return;
+            }
+        }
+        self.playlists += 1;
+    }
+
+    fn progress(&mut self, progress: Progress) {
+        let percent = progress.percent().map(|percent| percent as u32);
+        if percent != self.last_percent {
+            self.last_percent = percent;
+            let report = self.report();
+            if let Some(on_progress) = &mut self.on_progress {
+                on_progress(&report);
+            }
+        }
+    }
+}
+
+/// Readies the database for another import from `source` to merge into. Its playlists are
+/// replaced wholesale, its tracks are flagged as removed until the import finds them again.
+/// Rows from other sources and playlists made in the app are left alone.
+pub fn prepare_reimport(conn: &Connection, source: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM playlist_items
+        WHERE playlist_id IN (SELECT id FROM playlists WHERE source = ?1)",
+        [source],
+    )?;
+    conn.execute("DELETE FROM playlists WHERE source = ?1", [source])?;
+    conn.execute("UPDATE tracks SET removed = 1 WHERE source = ?1", [source])?;
+    Ok(())
+}
+
+/// How many tracks from `source` the last import from it did not find.
+pub fn count_removed(conn: &Connection, source: &str) -> rusqlite::Result<usize> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM tracks WHERE removed = 1 AND source = ?1",
+        [source],
+        |row| row.get(0),
+    )
+}
+
+pub fn load_library_info(conn: &Connection) -> rusqlite::Result<Option<LibraryInfo>> {
+    conn.query_row(
+        "SELECT
+            library_persistent_id,
+            date,
+            application_version,
+            music_folder,
+            major_version,
+            minor_version,
+            features,
+            show_content_ratings
+        FROM library_info WHERE id = 0",
+        (),
+        |row| {
+            Ok(LibraryInfo {
+                library_persistent_id: row.get(0)?,
+                date: row.get(1)?,
+                application_version: row.get(2)?,
+                music_folder: row.get(3)?,
+                major_version: row.get(4)?,
+                minor_version: row.get(5)?,
+                features: row.get(6)?,
+                show_content_ratings: row.get(7)?,
+            })
+        },
+    )
+    .optional()
+}
+
+pub fn store_library_info(conn: &Connection, info: &LibraryInfo) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO library_info (
+            id,
+            library_persistent_id,
+            date,
+            application_version,
+            music_folder,
+            major_version,
+            minor_version,
+            features,
+            show_content_ratings
+        ) VALUES (
+            0, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+        );",
+        (
+            &info.library_persistent_id,
+            &info.date,
+            &info.application_version,
+            &info.music_folder,
+            &info.major_version,
+            &info.minor_version,
+            &info.features,
+            &info.show_content_ratings,
+        ),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playlist_files::insert_playlist;
+    use crate::playlists::load_playlists;
+    use crate::schema::migrate;
+    use itunes_xml::{visit_rekordbox_xml, visit_traktor_nml};
+
+    const REKORDBOX: &str = "../itunes-xml/tests/fixtures/rekordbox.xml";
+    const TRAKTOR: &str = "../itunes-xml/tests/fixtures/collection.nml";
+
+    fn import(conn: &Connection) -> (usize, usize) {
+        prepare_reimport(conn, REKORDBOX_SOURCE).unwrap();
+        let mut importer = LibraryImporter::new(conn, REKORDBOX_SOURCE);
+        visit_rekordbox_xml(REKORDBOX, true, &mut importer).unwrap();
+        assert!(importer.error.is_none(), "{:?}", importer.error);
+        (importer.added, importer.updated)
+    }
+
+    #[test]
+    fn reimport_keeps_local_edits() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(import(&conn), (3, 0));
+
+        conn.execute("UPDATE tracks SET rating = 20 WHERE id = 12345", ())
+            .unwrap();
+        conn.execute("UPDATE tracks SET name = 'Old' WHERE id = 12346", ())
+            .unwrap();
+        assert_eq!(import(&conn), (0, 1));
+
+        let (rating, name): (i64, String) = conn
+            .query_row(
+                "SELECT rating, (SELECT name FROM tracks WHERE id = 12346)
+                FROM tracks WHERE id = 12345",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(rating, 20);
+        assert_eq!(name, "Blue Monday");
+        assert_eq!(count_removed(&conn, REKORDBOX_SOURCE).unwrap(), 0);
+    }
+
+    #[test]
+    fn reimport_leaves_other_sources() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        import(&conn);
+        let local = insert_playlist(&conn, "Mine", &[12347, 12345]).unwrap();
+        let mut importer = LibraryImporter::new(&conn, FOLDER_SOURCE);
+        importer.track(Track {
+            id: 12345,
+            location: Some("file:///Music/Scanned.mp3".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(importer.added, 1);
+
+        import(&conn);
+
+        let playlists = load_playlists(&conn).unwrap();
+        let mine = playlists.iter().find(|playlist| playlist.id == local).unwrap();
+        assert_eq!(mine.items, vec![12347, 12345]);
+        assert_eq!(playlists.len(), 4);
+        assert_eq!(count_removed(&conn, REKORDBOX_SOURCE).unwrap(), 0);
+        assert_eq!(count_removed(&conn, FOLDER_SOURCE).unwrap(), 0);
+    }
+
+    #[test]
+    fn formats_reimport_separately() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        import(&conn);
+
+        prepare_reimport(&conn, TRAKTOR_SOURCE).unwrap();
+        let mut importer = LibraryImporter::new(&conn, TRAKTOR_SOURCE);
+        visit_traktor_nml(TRAKTOR, true, &mut importer).unwrap();
+        assert!(importer.error.is_none(), "{:?}", importer.error);
+        assert_eq!((importer.added, importer.playlists), (3, 3));
+
+        assert_eq!(import(&conn), (0, 0));
+        assert_eq!(load_playlists(&conn).unwrap().len(), 6);
+        assert_eq!(count_removed(&conn, REKORDBOX_SOURCE).unwrap(), 0);
+        assert_eq!(count_removed(&conn, TRAKTOR_SOURCE).unwrap(), 0);
+    }
+
+    #[test]
+    fn fills_smart_playlists_from_stored_tracks() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        import(&conn);
+        conn.execute("UPDATE tracks SET rating = 80 WHERE id = 12346", ())
+            .unwrap();
+
+        // Live updating, matching rules, no limit: Rating (0x19) greater than (0x10) 60
+        let mut info = vec![0; 14];
+        info[..2].copy_from_slice(&[1, 1]);
+        let mut criteria = vec![0; 136 + 56 + 68];
+        criteria[..4].copy_from_slice(b"SLst");
+        criteria[8..12].copy_from_slice(&1u32.to_be_bytes());
+        criteria[136..140].copy_from_slice(&0x19u32.to_be_bytes());
+        criteria[140..144].copy_from_slice(&0x10u32.to_be_bytes());
+        criteria[188..192].copy_from_slice(&68u32.to_be_bytes());
+        criteria[192..200].copy_from_slice(&60i64.to_be_bytes());
+
+        let mut importer = LibraryImporter::new(&conn, REKORDBOX_SOURCE);
+        importer.playlist(Playlist {
+            id: 99,
+            name: "Top Rated".to_string(),
+            persistent_id: "5B5D4C3F2E1D0C9A".to_string(),
+            items: vec![12345],
+            smart_info: Some(info),
+            smart_criteria: Some(criteria),
+            ..Default::default()
+        });
+        assert!(importer.error.is_none(), "{:?}", importer.error);
+
+        let playlists = load_playlists(&conn).unwrap();
+        let smart = playlists.iter().find(|playlist| playlist.id == 99).unwrap();
+        assert_eq!(smart.items, vec![12345, 12346]);
+    }
+}
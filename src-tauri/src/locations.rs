@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use rusqlite::Connection;
+use url::Url;
+
+use itunes_xml::{remap_location, LibraryInfo, LocationRule};
+use types::LocationReport;
+
+/// Number of unresolved locations listed in a [`LocationReport`].
+const MISSING_SAMPLES: usize = 20;
+
+pub fn load_location_rules(conn: &Connection) -> rusqlite::Result<Vec<LocationRule>> {
+    let mut statement =
+        conn.prepare("SELECT source, target FROM location_rules ORDER BY position")?;
+    let rules = statement.query_map((), |row| {
+        Ok(LocationRule {
+            from: row.get(0)?,
+            to: row.get(1)?,
+        })
+    })?;
+    rules.collect()
+}
+
+pub fn store_location_rules(conn: &Connection, rules: &[LocationRule]) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM location_rules", ())?;
+    for (position, rule) in rules.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO location_rules (position, source, target) VALUES (?1, ?2, ?3);",
+            (position, &rule.from, &rule.to),
+        )?;
+    }
+    Ok(())
+}
+
+/// Applies the rules to a stored location, which is left as is when none match.
+pub fn resolve_location(location: &str, rules: &[LocationRule], info: &LibraryInfo) -> String {
+    remap_location(location, rules, info).unwrap_or_else(|| location.to_string())
+}
+
+pub fn location_path(location: &str) -> Option<PathBuf> {
+    Url::parse(location).ok()?.to_file_path().ok()
+}
+
+/// Counts how many imported locations the rules rewrite and how many then exist on disk.
+pub fn check_locations(
+    conn: &Connection,
+    rules: &[LocationRule],
+    info: &LibraryInfo,
+) -> rusqlite::Result<LocationReport> {
+    let mut statement =
+        conn.prepare("SELECT location FROM tracks WHERE location IS NOT NULL AND removed = 0")?;
+    let locations = statement.query_map((), |row| row.get::<_, String>(0))?;
+
+    let mut report = LocationReport::default();
+    for location in locations {
+        let location = location?;
+        report.total += 1;
+        let remapped = remap_location(&location, rules, info);
+        if remapped.is_some() {
+            report.remapped += 1;
+        }
+
+        let location = remapped.unwrap_or(location);
+        match location_path(&location) {
+            Some(path) if path.exists() => report.resolved += 1,
+            _ if report.missing.len() < MISSING_SAMPLES => report.missing.push(location),
+            _ => (),
+        }
+    }
+    Ok(report)
+}
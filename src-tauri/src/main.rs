@@ -2,16 +2,43 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use rodio::{Decoder, OutputStream, Sink};
 use rusqlite::{params_from_iter, Connection, Result};
-use tauri::State;
-use url::Url;
+use tauri::api::dialog::blocking::FileDialogBuilder;
+use tauri::{State, Window};
 
-use itunes_xml::{parse_itunes_xml, Track};
-use types::QueryParams;
+use itunes_xml::{
+    diff_libraries, parse_itunes_xml_lenient, playlist_tree, visit_itunes_xml, visit_rekordbox_xml,
+    visit_traktor_nml, write_rekordbox_xml, LibraryDiff, LibraryInfo, LocationRule, ParseReport,
+    Playlist, PlaylistNode, Track,
+};
+use itunes_xml::{read_playlist_file, write_playlist_file, PlaylistEntry, PlaylistFormat};
+use types::{ImportProgress, LibraryFormat, LocationReport, QueryParams};
+
+use crate::import::{
+    count_removed, library_source, load_library_info, prepare_reimport, store_library_info,
+    LibraryImporter, FOLDER_SOURCE,
+};
+use crate::locations::{
+    check_locations, load_location_rules, location_path, resolve_location, store_location_rules,
+};
+use crate::playlist_files::{entry_path, insert_playlist, playlist_entry, track_ids_by_path};
+use crate::playlists::{load_playlist_tracks, load_playlists};
+use crate::scan::scan_folder;
+use crate::schema::migrate;
+use crate::tracks::{load_library, track_from_row, TRACK_COLUMNS};
+
+mod import;
+mod locations;
+mod playlist_files;
+mod playlists;
+mod scan;
+mod schema;
+mod tracks;
 
 struct AppState {
     pub db: Arc<Mutex<Connection>>,
@@ -42,10 +69,14 @@ fn stop_command(app_state: State<AppState>) -> Result<(), String> {
 fn play_track_command(path: &str, app_state: State<AppState>) -> Result<(), String> {
     app_state.sink.stop();
 
-    let file_url = Url::parse(path).map_err(|err| err.to_string())?;
-    let path_buf = file_url
-        .to_file_path()
-        .map_err(|_| "Failed to parse location")?;
+    // Locations are stored as exported, the rules apply as they are now
+    let location = {
+        let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+        let rules = load_location_rules(&conn).map_err(|err| err.to_string())?;
+        let info = load_library_info(&conn).map_err(|err| err.to_string())?;
+        resolve_location(path, &rules, &info.unwrap_or_default())
+    };
+    let path_buf = location_path(&location).ok_or("Failed to parse location")?;
     let file = File::open(path_buf).map_err(|err| err.to_string())?;
     let source = Decoder::new(BufReader::new(file)).map_err(|err| err.to_string())?;
     app_state.sink.append(source);
@@ -53,44 +84,292 @@ fn play_track_command(path: &str, app_state: State<AppState>) -> Result<(), Stri
     Ok(())
 }
 
+/// Merges a library export in `format` into the database in one transaction, so a failed
+/// import leaves the previous library as it was. Progress is emitted as `import-progress`
+/// events. Returns a summary along with the problems found in the export.
+#[tauri::command]
+async fn parse_itunes_xml_command(
+    path: &str,
+    format: LibraryFormat,
+    window: Window,
+    app_state: State<'_, AppState>,
+) -> Result<(String, ParseReport), String> {
+    let mut conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    let previous_info = load_library_info(&conn).map_err(|err| err.to_string())?;
+
+    let source = library_source(format);
+    let transaction = conn.transaction().map_err(|err| err.to_string())?;
+    prepare_reimport(&transaction, source).map_err(|err| err.to_string())?;
+
+    let mut importer = LibraryImporter::new(&transaction, source)
+        .on_progress(|progress| emit_import_progress(&window, progress));
+    let report = match format {
+        LibraryFormat::Itunes => visit_itunes_xml(path, true, &mut importer),
+        LibraryFormat::Rekordbox => visit_rekordbox_xml(path, true, &mut importer),
+        LibraryFormat::Traktor => visit_traktor_nml(path, true, &mut importer),
+    }
+    .map_err(|err| err.to_string())?;
+    if let Some(err) = importer.error.take() {
+        return Err(err.to_string());
+    }
+
+    // Only iTunes exports have the header, other formats keep the stored one like a folder scan
+    let info = LibraryInfo::from_metadata(&importer.metadata);
+    let has_header = format == LibraryFormat::Itunes;
+    if has_header || previous_info.is_none() {
+        store_library_info(&transaction, &info).map_err(|err| err.to_string())?;
+    }
+
+    let removed = count_removed(&transaction, source).map_err(|err| err.to_string())?;
+    let mut summary = format!(
+        "Imported {} tracks ({} new, {} updated), {} removed, skipped {}",
+        importer.imported,
+        importer.added,
+        importer.updated,
+        removed,
+        report.skipped_tracks
+    );
+    if report.skipped_playlists > 0 {
+        summary.push_str(&format!(" ({} playlists skipped)", report.skipped_playlists));
+    }
+    if let Some(previous_id) = previous_info.and_then(|info| info.library_persistent_id) {
+        if has_header && info.library_persistent_id.as_ref() != Some(&previous_id) {
+            summary.push_str(&format!(", replaced library {}", previous_id));
+        }
+    }
+
+    let progress = importer.report();
+    drop(importer);
+    transaction.commit().map_err(|err| err.to_string())?;
+    emit_import_progress(&window, &progress);
+    Ok((summary, report))
+}
+
+/// Adds the audio files below a folder from their tags, merged with earlier scans. Imported
+/// libraries and their playlists are left alone. The summary lists the paths that could not be
+/// read.
 #[tauri::command]
-fn parse_itunes_xml_command(path: &str, app_state: State<AppState>) -> Result<(), String> {
-    println!("{:?}", path);
-    let library = parse_itunes_xml(path).map_err(|err| err.to_string())?;
+async fn scan_folder_command(
+    path: &str,
+    window: Window,
+    app_state: State<'_, AppState>,
+) -> Result<String, String> {
+    let mut conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    let transaction = conn.transaction().map_err(|err| err.to_string())?;
+
+    let mut importer = LibraryImporter::new(&transaction, FOLDER_SOURCE)
+        .on_progress(|progress| emit_import_progress(&window, progress));
+    let skipped = scan_folder(Path::new(path), &mut importer).map_err(|err| err.to_string())?;
+    if let Some(err) = importer.error.take() {
+        return Err(err.to_string());
+    }
+
+    // The folder only stands in as Music Folder while no library was imported
+    if load_library_info(&transaction)
+        .map_err(|err| err.to_string())?
+        .is_none()
+    {
+        let info = LibraryInfo::from_metadata(&importer.metadata);
+        store_library_info(&transaction, &info).map_err(|err| err.to_string())?;
+    }
+
+    let mut summary = format!(
+        "Imported {} tracks ({} new, {} updated), skipped {}",
+        importer.imported,
+        importer.added,
+        importer.updated,
+        skipped.len()
+    );
+    for (index, skipped) in skipped.iter().enumerate() {
+        let separator = if index == 0 { ": " } else { "; " };
+        summary.push_str(&format!(
+            "{}{} ({})",
+            separator,
+            skipped.path.display(),
+            skipped.reason
+        ));
+    }
+
+    let progress = importer.report();
+    drop(importer);
+    transaction.commit().map_err(|err| err.to_string())?;
+    emit_import_progress(&window, &progress);
+    Ok(summary)
+}
+
+fn emit_import_progress(window: &Window, progress: &ImportProgress) {
+    if let Err(err) = window.emit("import-progress", progress) {
+        eprintln!("Could not emit import progress: {}", err);
+    }
+}
+
+#[tauri::command]
+fn location_rules_command(app_state: State<AppState>) -> Result<Vec<LocationRule>, String> {
     let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    load_location_rules(&conn).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn set_location_rules_command(
+    rules: Vec<LocationRule>,
+    app_state: State<AppState>,
+) -> Result<(), String> {
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    store_location_rules(&conn, &rules).map_err(|err| err.to_string())
+}
 
-    if conn.execute("DROP TABLE tracks", ()).is_ok() {
-        println!("Existing table dropped");
+/// Dry run of `rules` against the imported locations, nothing is saved.
+#[tauri::command]
+fn check_locations_command(
+    rules: Vec<LocationRule>,
+    app_state: State<AppState>,
+) -> Result<LocationReport, String> {
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    let info = load_library_info(&conn).map_err(|err| err.to_string())?;
+    check_locations(&conn, &rules, &info.unwrap_or_default()).map_err(|err| err.to_string())
+}
+
+/// Writes the given playlists to a Rekordbox XML file picked in a save dialog, with locations
+/// remapped by the rules. Returns its path or `None` when the dialog was cancelled.
+#[tauri::command]
+async fn export_rekordbox_command(
+    playlist_ids: Vec<u64>,
+    app_state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Export playlists for Rekordbox")
+        .set_file_name("rekordbox.xml")
+        .add_filter("Rekordbox XML", &["xml"])
+        .save_file()
+    else {
+        return Ok(None);
     };
 
-    conn.execute(
-        "CREATE TABLE tracks (
-            id          INTEGER PRIMARY KEY,
-            name        TEXT,
-            artist      TEXT,
-            bpm         INTEGER,
-            location    TEXT
-        )",
-        (), // empty list of parameters.
-    )
+    let library = {
+        let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+        let mut library = load_library(&conn).map_err(|err| err.to_string())?;
+        let rules = load_location_rules(&conn).map_err(|err| err.to_string())?;
+        let info = load_library_info(&conn).map_err(|err| err.to_string())?;
+        let info = info.unwrap_or_default();
+        for track in library.tracks.values_mut() {
+            if let Some(location) = &track.location {
+                track.location = Some(resolve_location(location, &rules, &info));
+            }
+        }
+        library
+    };
+    let file = File::create(&path).map_err(|err| err.to_string())?;
+    write_rekordbox_xml(&library, &playlist_ids, BufWriter::new(file))
         .map_err(|err| err.to_string())?;
+    Ok(Some(path.to_string_lossy().to_string()))
+}
 
-    for (id, track) in &library.tracks {
-        conn.execute(
-            "INSERT INTO tracks (
-                id,
-                name,
-                artist,
-                bpm,
-                location
-            ) VALUES (
-                ?1, ?2, ?3, ?4, ?5
-            );",
-            (id, &track.name, &track.artist, &track.bpm, &track.location),
-        )
-            .map_err(|err| err.to_string())?;
-    }
-    Ok(())
+/// Adds a playlist from an M3U, M3U8 or PLS file picked in a dialog, with the entries found in
+/// the library. Returns a summary, or `None` when the dialog was cancelled.
+#[tauri::command]
+async fn import_playlist_file_command(
+    app_state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Import a playlist")
+        .add_filter("Playlists", &["m3u", "m3u8", "pls"])
+        .pick_file()
+    else {
+        return Ok(None);
+    };
+
+    let format = PlaylistFormat::from_path(&path).ok_or("Unsupported playlist file")?;
+    let bytes = std::fs::read(&path).map_err(|err| err.to_string())?;
+    let entries = read_playlist_file(&String::from_utf8_lossy(&bytes), format);
+    let playlist_dir = path.parent().unwrap_or(Path::new(""));
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    let rules = load_location_rules(&conn).map_err(|err| err.to_string())?;
+    let info = load_library_info(&conn).map_err(|err| err.to_string())?;
+    let ids = track_ids_by_path(&conn, &rules, &info.unwrap_or_default())
+        .map_err(|err| err.to_string())?;
+    let items: Vec<u64> = entries
+        .iter()
+        .filter_map(|entry| ids.get(&entry_path(&entry.location, playlist_dir)?).copied())
+        .collect();
+    insert_playlist(&conn, &name, &items).map_err(|err| err.to_string())?;
+
+    Ok(Some(format!(
+        "Imported playlist {} with {} of {} entries",
+        name,
+        items.len(),
+        entries.len()
+    )))
+}
+
+/// Writes a playlist, or everything in a folder, to an M3U, M3U8 or PLS file picked in a save
+/// dialog. Paths are absolute, or relative to the playlist file with `relative`.
+#[tauri::command]
+async fn export_playlist_file_command(
+    playlist_id: u64,
+    relative: bool,
+    app_state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Export playlist")
+        .add_filter("Extended M3U", &["m3u8"])
+        .add_filter("M3U", &["m3u"])
+        .add_filter("PLS", &["pls"])
+        .save_file()
+    else {
+        return Ok(None);
+    };
+
+    let (library, rules, info) = {
+        let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+        let library = load_library(&conn).map_err(|err| err.to_string())?;
+        let rules = load_location_rules(&conn).map_err(|err| err.to_string())?;
+        let info = load_library_info(&conn).map_err(|err| err.to_string())?;
+        (library, rules, info.unwrap_or_default())
+    };
+    let playlist = library
+        .playlists
+        .get(&playlist_id)
+        .ok_or("Unknown playlist")?;
+    let track_ids = match playlist.folder {
+        Some(true) => library
+            .playlist_tree()
+            .iter()
+            .find_map(|node| node.find(&playlist.persistent_id))
+            .map(|node| node.track_ids.clone())
+            .unwrap_or_default(),
+        _ => playlist.items.clone(),
+    };
+
+    let playlist_dir = path.parent().unwrap_or(Path::new(""));
+    let relative_to = relative.then_some(playlist_dir);
+    let entries: Vec<PlaylistEntry> = track_ids
+        .iter()
+        .filter_map(|id| library.tracks.get(id))
+        .filter_map(|track| playlist_entry(track, &rules, &info, relative_to))
+        .collect();
+    let format = PlaylistFormat::from_path(&path).unwrap_or(PlaylistFormat::M3u8);
+    let file = File::create(&path).map_err(|err| err.to_string())?;
+    write_playlist_file(&entries, format, BufWriter::new(file)).map_err(|err| err.to_string())?;
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Compares the stored library with a newer export, to preview what a re-import would change.
+#[tauri::command]
+fn diff_libraries_command(
+    new_path: &str,
+    app_state: State<AppState>,
+) -> Result<LibraryDiff, String> {
+    let old = {
+        let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+        load_library(&conn).map_err(|err| err.to_string())?
+    };
+    let (new, _) = parse_itunes_xml_lenient(new_path).map_err(|err| err.to_string())?;
+    Ok(diff_libraries(&old, &new))
 }
 
 #[tauri::command]
@@ -98,9 +377,42 @@ fn is_library_loaded_command(
     app_state: State<AppState>,
 ) -> Result<bool, String> {
     let conn = app_state.db.lock().map_err(|err| err.to_string())?;
-    let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name=(?);").map_err(|err| err.to_string())?;
-    let mut rows = stmt.query(["tracks"]).map_err(|err| err.to_string())?;
-    Ok(rows.next().map_err(|err| err.to_string())?.is_some())
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM tracks) OR EXISTS (SELECT 1 FROM library_info)",
+        (),
+        |row| row.get(0),
+    )
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn library_info_command(app_state: State<AppState>) -> Result<Option<LibraryInfo>, String> {
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    load_library_info(&conn).map_err(|err| err.to_string())
+}
+
+/// Every playlist and folder, ordered by ID, with the IDs of its tracks.
+#[tauri::command]
+fn playlists_command(app_state: State<AppState>) -> Result<Vec<Playlist>, String> {
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    load_playlists(&conn).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn fetch_playlist_tracks_command(
+    playlist_id: u64,
+    app_state: State<AppState>,
+) -> Result<Vec<Track>, String> {
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    load_playlist_tracks(&conn, playlist_id).map_err(|err| err.to_string())
+}
+
+/// Playlist folders as shown in iTunes, e.g. "Gigs / 2023 / Club X".
+#[tauri::command]
+fn playlist_tree_command(app_state: State<AppState>) -> Result<Vec<PlaylistNode>, String> {
+    let conn = app_state.db.lock().map_err(|err| err.to_string())?;
+    let playlists = load_playlists(&conn).map_err(|err| err.to_string())?;
+    Ok(playlist_tree(&playlists))
 }
 
 #[tauri::command]
@@ -110,7 +422,8 @@ fn fetch_tracks_command(
 ) -> Result<Vec<Track>, String> {
     let conn = app_state.db.lock().map_err(|err| err.to_string())?;
 
-    let mut query_parts = vec![];
+    // Tracks gone from the source stay stored but aren't listed
+    let mut query_parts = vec!["( removed = 0 )"];
     let mut params: Vec<String> = vec![];
 
     if let Some(title) = query.title {
@@ -123,6 +436,16 @@ fn fetch_tracks_command(
         params.push(artist.split_whitespace().collect::<Vec<&str>>().join("%"));
     };
 
+    if let Some(album) = query.album {
+        query_parts.push("(LOWER( album ) LIKE '%' || (?) || '%')");
+        params.push(album.split_whitespace().collect::<Vec<&str>>().join("%"));
+    };
+
+    if let Some(genre) = query.genre {
+        query_parts.push("(LOWER( genre ) LIKE '%' || (?) || '%')");
+        params.push(genre.split_whitespace().collect::<Vec<&str>>().join("%"));
+    };
+
     if let Some(bpm) = query.bpm_min {
         query_parts.push("( bpm >= (?) )");
         params.push(bpm.to_string());
@@ -138,12 +461,9 @@ fn fetch_tracks_command(
         params.push(location.split_whitespace().collect::<Vec<&str>>().join("%"));
     };
 
-    let wheres = match query_parts.is_empty() {
-        true => "".to_string(),
-        false => format!("WHERE {}", query_parts.join(" AND ")),
-    };
+    let wheres = format!("WHERE {}", query_parts.join(" AND "));
 
-    let full_query = format!("SELECT * FROM tracks {} LIMIT (?);", wheres);
+    let full_query = format!("SELECT {} FROM tracks {} LIMIT (?);", TRACK_COLUMNS, wheres);
     params.push(query.limit.to_string());
 
     println!("{full_query:?}, {params:?}");
@@ -151,20 +471,12 @@ fn fetch_tracks_command(
         .prepare(full_query.as_str())
         .map_err(|err| err.to_string())?;
     let library_iter = statement
-        .query_map(params_from_iter(params.iter()), |row| {
-            let track = Track {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                artist: row.get(2)?,
-                bpm: row.get(3)?,
-                location: row.get(4)?,
-                ..Default::default()
-            };
-            Ok(track)
-        })
+        .query_map(params_from_iter(params.iter()), track_from_row)
         .map_err(|err| err.to_string())?;
 
-    Ok(library_iter.map(|row| row.unwrap()).collect())
+    library_iter
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(|err| err.to_string())
 }
 
 // TODO Consider file access via tauri command alternative
@@ -185,7 +497,8 @@ fn fetch_tracks_command(
 
 fn main() {
     // Open DB
-    let conn = Connection::open("db.sqlite").expect("Database open failed");
+    let mut conn = Connection::open("db.sqlite").expect("Database open failed");
+    migrate(&mut conn).expect("Database migration failed");
 
     // Open sound device
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
@@ -199,6 +512,18 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             is_library_loaded_command,
             parse_itunes_xml_command,
+            scan_folder_command,
+            diff_libraries_command,
+            export_rekordbox_command,
+            import_playlist_file_command,
+            export_playlist_file_command,
+            library_info_command,
+            playlist_tree_command,
+            playlists_command,
+            fetch_playlist_tracks_command,
+            location_rules_command,
+            set_location_rules_command,
+            check_locations_command,
             fetch_tracks_command,
             play_track_command,
             pause_command,
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use rusqlite::Connection;
+
+use itunes_xml::{LibraryInfo, LocationRule, PlaylistEntry, Track};
+
+use crate::import::LOCAL_SOURCE;
+use crate::locations::{location_path, resolve_location};
+
+/// Paths of the imported tracks after applying the location rules, to match playlist entries.
+pub fn track_ids_by_path(
+    conn: &Connection,
+    rules: &[LocationRule],
+    info: &LibraryInfo,
+) -> rusqlite::Result<HashMap<PathBuf, u64>> {
+    let mut statement =
+        conn.prepare("SELECT id, location FROM tracks WHERE location IS NOT NULL AND removed = 0")?;
+    let rows = statement.query_map((), |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+
+    let mut ids = HashMap::new();
+    for row in rows {
+        let (id, location) = row?;
+        if let Some(path) = location_path(&resolve_location(&location, rules, info)) {
+            ids.insert(path, id);
+        }
+    }
+    Ok(ids)
+}
+
+/// The file a playlist entry refers to, `None` for streams and other non-file URLs.
+pub fn entry_path(location: &str, playlist_dir: &Path) -> Option<PathBuf> {
+    if location.starts_with("file:") {
+        return location_path(location);
+    }
+    if location.contains("://") {
+        return None;
+    }
+    Some(normalize(&playlist_dir.join(location)))
+}
+
+/// An exported entry for `track`, with a path relative to `relative_to` when given. Tracks
+/// without a location are left out.
+pub fn playlist_entry(
+    track: &Track,
+    rules: &[LocationRule],
+    info: &LibraryInfo,
+    relative_to: Option<&Path>,
+) -> Option<PlaylistEntry> {
+    let location = resolve_location(track.location.as_deref()?, rules, info);
+    let location = match (location_path(&location), relative_to) {
+        (Some(path), Some(base)) => relative_path(&path, base).to_string_lossy().to_string(),
+        (Some(path), None) => path.to_string_lossy().to_string(),
+        (None, _) => location,
+    };
+    let title = match (&track.artist, &track.name) {
+        (Some(artist), Some(name)) => Some(format!("{} - {}", artist, name)),
+        (_, name) => name.clone(),
+    };
+    Some(PlaylistEntry {
+        location,
+        title,
+        duration: track.total_time.map(|millis| millis / 1000),
+    })
+}
+
+/// Adds a top level playlist after the imported ones, returning its ID. It is stored with the
+/// local source, so re-imports keep it.
+pub fn insert_playlist(conn: &Connection, name: &str, items: &[u64]) -> rusqlite::Result<u64> {
+    let id: u64 = conn.query_row(
+        "SELECT COALESCE(MAX(id), 0) + 1 FROM playlists",
+        (),
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "INSERT INTO playlists (
+            id,
+            persistent_id,
+            parent_persistent_id,
+            name,
+            folder,
+            source
+        ) VALUES (?1, ?2, NULL, ?3, 0, ?4);",
+        (id, format!("{:016X}", id), name, LOCAL_SOURCE),
+    )?;
+    for (position, track_id) in items.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO playlist_items (playlist_id, position, track_id) VALUES (?1, ?2, ?3);",
+            (id, position, track_id),
+        )?;
+    }
+    Ok(id)
+}
+
+/// `path` relative to the directory `base`, or `path` itself when they share no root.
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path_components: Vec<Component> = path.components().collect();
+    let base_components: Vec<Component> = base.components().collect();
+    let common = path_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path.to_path_buf();
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &path_components[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+/// Resolves `.` and `..` without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+
+use itunes_xml::{Playlist, Track};
+
+use crate::tracks::{track_from_row, TRACK_COLUMNS};
+
+/// `preferred` when no stored playlist has it, otherwise one past the highest stored ID.
+pub fn free_playlist_id(conn: &Connection, preferred: u64) -> rusqlite::Result<u64> {
+    conn.prepare_cached(
+        "SELECT CASE
+            WHEN EXISTS (SELECT 1 FROM playlists WHERE id = ?1)
+                THEN (SELECT MAX(id) + 1 FROM playlists)
+            ELSE ?1
+        END",
+    )?
+    .query_row([preferred], |row| row.get(0))
+}
+
+/// Reads playlists with their items back from the database, ordered by ID.
+pub fn load_playlists(conn: &Connection) -> rusqlite::Result<Vec<Playlist>> {
+    let mut items: HashMap<u64, Vec<u64>> = HashMap::new();
+    let mut statement = conn.prepare(
+        "SELECT playlist_id, track_id FROM playlist_items ORDER BY playlist_id, position",
+    )?;
+    let rows = statement.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (playlist_id, track_id) = row?;
+        items.entry(playlist_id).or_default().push(track_id);
+    }
+
+    let mut statement = conn.prepare(
+        "SELECT
+            id,
+            persistent_id,
+            parent_persistent_id,
+            name,
+            folder
+        FROM playlists ORDER BY id",
+    )?;
+    let playlists = statement.query_map((), |row| {
+        let id = row.get(0)?;
+        Ok(Playlist {
+            id,
+            persistent_id: row.get(1)?,
+            parent_persistent_id: row.get(2)?,
+            name: row.get(3)?,
+            folder: row.get(4)?,
+            items: items.remove(&id).unwrap_or_default(),
+            ..Default::default()
+        })
+    })?;
+    playlists.collect()
+}
+
+/// The playlist's tracks in playlist order, repeated where the playlist repeats them. Folders
+/// have no items of their own.
+pub fn load_playlist_tracks(conn: &Connection, playlist_id: u64) -> rusqlite::Result<Vec<Track>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT {} FROM playlist_items
+        JOIN tracks ON tracks.id = playlist_items.track_id
+        WHERE playlist_items.playlist_id = (?)
+        ORDER BY playlist_items.position",
+        TRACK_COLUMNS
+    ))?;
+    let tracks = statement.query_map([playlist_id], track_from_row)?;
+    tracks.collect()
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use lofty::{Accessor, AudioFile, ItemKey, LoftyError, TaggedFileExt};
+use url::Url;
+
+use itunes_xml::{Element, LibraryVisitor, Progress, Track};
+
+/// Extensions of the files a folder scan reads tags from.
+const AUDIO_EXTENSIONS: [&str; 4] = ["mp3", "m4a", "flac", "ogg"];
+
+/// A file or folder a scan left out because it could not be read.
+#[derive(Debug)]
+pub struct SkippedPath {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl SkippedPath {
+    fn new(path: &Path, reason: impl ToString) -> Self {
+        SkippedPath {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Reads the tags of every audio file below `dir` into `visitor`, as if they came from a
+/// library export with `dir` as its Music Folder. Returns the files and folders that could not
+/// be read; only an unreadable `dir` fails the scan.
+pub fn scan_folder<V: LibraryVisitor>(dir: &Path, visitor: &mut V) -> io::Result<Vec<SkippedPath>> {
+    let mut skipped = Vec::new();
+    let files = audio_files(dir, &mut skipped)?;
+    if let Ok(url) = Url::from_directory_path(dir) {
+        let value = Element::String(Some(url.to_string()));
+        visitor.metadata("Music Folder".to_string(), value);
+    }
+
+    for (index, path) in files.iter().enumerate() {
+        match read_track(path, index as u64 + 1) {
+            Ok(track) => visitor.track(track),
+            Err(err) => skipped.push(SkippedPath::new(path, err)),
+        }
+        // Counted in files, their sizes say little about how long reading tags takes
+        visitor.progress(Progress {
+            bytes_read: index as u64 + 1,
+            total_bytes: Some(files.len() as u64),
+        });
+    }
+    Ok(skipped)
+}
+
+/// Audio files below `dir` in path order. Hidden entries and symlinked folders are left out,
+/// unreadable subfolders and entries are added to `skipped`.
+pub fn audio_files(dir: &Path, skipped: &mut Vec<SkippedPath>) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = match fs::read_dir(&current) {
+            Ok(entries) => entries,
+            Err(err) if current == dir => return Err(err),
+            Err(err) => {
+                skipped.push(SkippedPath::new(&current, err));
+                continue;
+            }
+        };
+        for entry in entries {
+            // The entry's name is unknown, so the folder stands in for it
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    skipped.push(SkippedPath::new(&current, err));
+                    continue;
+                }
+            };
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => pending.push(path),
+                Ok(_) if is_audio_file(&path) => files.push(path),
+                Ok(_) => (),
+                Err(err) => skipped.push(SkippedPath::new(&path, err)),
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            AUDIO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+/// Builds a track from the file's tags and audio properties. Files without a title are named
+/// after the file.
+pub fn read_track(path: &Path, id: u64) -> Result<Track, LoftyError> {
+    let tagged_file = lofty::read_from_path(path)?;
+    let properties = tagged_file.properties();
+    let mut track = Track {
+        id,
+        location: Url::from_file_path(path).ok().map(String::from),
+        size: fs::metadata(path).ok().map(|metadata| metadata.len() as i64),
+        total_time: Some(properties.duration().as_millis() as i64),
+        bit_rate: properties.audio_bitrate().map(i64::from),
+        sample_rate: properties.sample_rate().map(i64::from),
+        ..Default::default()
+    };
+
+    if let Some(tag) = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag())
+    {
+        track.name = tag.title().map(|title| title.to_string());
+        track.artist = tag.artist().map(|artist| artist.to_string());
+        track.album = tag.album().map(|album| album.to_string());
+        track.genre = tag.genre().map(|genre| genre.to_string());
+        track.album_artist = tag.get_string(&ItemKey::AlbumArtist).map(str::to_string);
+        track.track_number = tag.track().map(i64::from);
+        track.disc_number = tag.disk().map(i64::from);
+        track.year = tag.year().map(i64::from);
+        // MP4 stores an integer, ID3 and Vorbis comments text that may have decimals
+        track.bpm = tag
+            .get_string(&ItemKey::Bpm)
+            .or_else(|| tag.get_string(&ItemKey::IntegerBpm))
+            .and_then(|bpm| bpm.trim().parse::<f64>().ok())
+            .map(|bpm| bpm.round() as i64);
+        // Under Rekordbox's name, like the key from a Traktor collection
+        if let Some(key) = tag.get_string(&ItemKey::InitialKey) {
+            let value = Element::String(Some(key.to_string()));
+            track.extra.insert("Tonality".to_string(), value);
+        }
+    }
+    if track.name.is_none() {
+        track.name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string());
+    }
+    Ok(track)
+}
@@ -0,0 +1,224 @@
+use rusqlite::{Connection, Transaction};
+
+/// Schema changes in the order they were introduced. `schema_version` records how many of
+/// them a database has had, new ones are only ever appended.
+const MIGRATIONS: [fn(&Transaction) -> rusqlite::Result<()>; 3] =
+    [baseline, removed_tracks, import_sources];
+
+/// Brings the database up to the current schema, each migration in its own transaction.
+/// Returns the schema version the database is at.
+pub fn migrate(conn: &mut Connection) -> rusqlite::Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        (),
+    )?;
+    let mut version: usize = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        (),
+        |row| row.get(0),
+    )?;
+
+    // A database from a newer build keeps its schema
+    for migration in MIGRATIONS.iter().skip(version) {
+        let transaction = conn.transaction()?;
+        migration(&transaction)?;
+        version += 1;
+        transaction.execute("DELETE FROM schema_version", ())?;
+        transaction.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            [version],
+        )?;
+        transaction.commit()?;
+    }
+    Ok(version)
+}
+
+/// The schema as it stood before versioning. Databases from then were created table by table,
+/// with fewer `tracks` columns depending on their age, so existing tables are rebuilt with
+/// their rows kept.
+fn baseline(transaction: &Transaction) -> rusqlite::Result<()> {
+    ensure_table(transaction, "tracks", TRACKS)?;
+    ensure_table(transaction, "playlists", PLAYLISTS)?;
+    ensure_table(transaction, "playlist_items", PLAYLIST_ITEMS)?;
+    ensure_table(transaction, "library_info", LIBRARY_INFO)?;
+    ensure_table(transaction, "location_rules", LOCATION_RULES)?;
+    Ok(())
+}
+
+/// Re-imports merge into the stored tracks, flagging the ones the source no longer has
+/// instead of deleting them, and look tracks up by persistent ID or location.
+fn removed_tracks(transaction: &Transaction) -> rusqlite::Result<()> {
+    transaction.execute(
+        "ALTER TABLE tracks ADD COLUMN removed INTEGER NOT NULL DEFAULT 0",
+        (),
+    )?;
+    transaction.execute(
+        "CREATE INDEX tracks_persistent_id ON tracks (persistent_id)",
+        (),
+    )?;
+    transaction.execute("CREATE INDEX tracks_location ON tracks (location)", ())?;
+    Ok(())
+}
+
+/// Re-imports only replace the tracks and playlists of the same source, so each library format
+/// and folder scans merge separately and playlists made in the app stay. Those are stored with
+/// the local source from now on; the rows stored so far are taken as iTunes imports, the
+/// format the app started out with.
+fn import_sources(transaction: &Transaction) -> rusqlite::Result<()> {
+    transaction.execute("ALTER TABLE tracks ADD COLUMN source TEXT", ())?;
+    transaction.execute("ALTER TABLE playlists ADD COLUMN source TEXT", ())?;
+    transaction.execute("UPDATE tracks SET source = 'itunes'", ())?;
+    transaction.execute("UPDATE playlists SET source = 'itunes'", ())?;
+    Ok(())
+}
+
+/// Creates `table`, or rebuilds it from `definition` when it lacks some of its columns,
+/// copying over the columns both versions have.
+fn ensure_table(transaction: &Transaction, table: &str, definition: &str) -> rusqlite::Result<()> {
+    let existing = columns(transaction, table)?;
+    if existing.is_empty() {
+        transaction.execute(definition, ())?;
+        return Ok(());
+    }
+
+    let rebuilt = format!("{}_migrated", table);
+    let definition = definition.replacen(
+        &format!("TABLE {} (", table),
+        &format!("TABLE {} (", rebuilt),
+        1,
+    );
+    transaction.execute(&definition, ())?;
+    let wanted = columns(transaction, &rebuilt)?;
+    if wanted.iter().all(|column| existing.contains(column)) {
+        transaction.execute(&format!("DROP TABLE {}", rebuilt), ())?;
+        return Ok(());
+    }
+
+    let common = wanted
+        .into_iter()
+        .filter(|column| existing.contains(column))
+        .collect::<Vec<_>>()
+        .join(", ");
+    transaction.execute(
+        &format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {}",
+            rebuilt, common, common, table
+        ),
+        (),
+    )?;
+    transaction.execute(&format!("DROP TABLE {}", table), ())?;
+    transaction.execute(&format!("ALTER TABLE {} RENAME TO {}", rebuilt, table), ())?;
+    Ok(())
+}
+
+/// Column names of `table`, empty when it does not exist.
+fn columns(transaction: &Transaction, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = transaction.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = statement.query_map((), |row| row.get(1))?;
+    names.collect()
+}
+
+const TRACKS: &str = "CREATE TABLE tracks (
+    id                     INTEGER PRIMARY KEY,
+    name                   TEXT,
+    artist                 TEXT,
+    album_artist           TEXT,
+    composer               TEXT,
+    genre                  TEXT,
+    album                  TEXT,
+    kind                   TEXT,
+    loved                  INTEGER,
+    disliked               INTEGER,
+    matched                INTEGER,
+    explicit               INTEGER,
+    compilation            INTEGER,
+    part_of_gapless_album  INTEGER,
+    movie                  INTEGER,
+    podcast                INTEGER,
+    unplayed               INTEGER,
+    comments               TEXT,
+    content_rating         TEXT,
+    size                   INTEGER,
+    total_time             INTEGER,
+    disc_number            INTEGER,
+    disc_count             INTEGER,
+    track_number           INTEGER,
+    track_count            INTEGER,
+    year                   INTEGER,
+    bpm                    INTEGER,
+    date_modified          TEXT,
+    date_added             TEXT,
+    bit_rate               INTEGER,
+    sample_rate            INTEGER,
+    equalizer              TEXT,
+    play_count             INTEGER,
+    play_date              TEXT,
+    play_date_utc          TEXT,
+    skip_count             INTEGER,
+    skip_date              TEXT,
+    release_date           TEXT,
+    normalization          INTEGER,
+    rating                 INTEGER,
+    rating_computed        INTEGER,
+    album_rating           INTEGER,
+    album_rating_computed  INTEGER,
+    artwork_count          INTEGER,
+    sort_name              TEXT,
+    sort_album             TEXT,
+    sort_album_artist      TEXT,
+    sort_composer          TEXT,
+    sort_artist            TEXT,
+    persistent_id          TEXT,
+    track_type             TEXT,
+    purchased              INTEGER,
+    music_video            INTEGER,
+    has_video              INTEGER,
+    hd                     INTEGER,
+    favorited              INTEGER,
+    location               TEXT,
+    file_folder_count      INTEGER,
+    library_folder_count   INTEGER,
+    volume_adjustment      INTEGER,
+    grouping               TEXT,
+    work                   TEXT,
+    movement_name          TEXT,
+    movement_number        INTEGER,
+    start_time             INTEGER,
+    stop_time              INTEGER,
+    extra                  TEXT,
+    tempo_markers          TEXT,
+    cue_points             TEXT
+)";
+
+const PLAYLISTS: &str = "CREATE TABLE playlists (
+    id                      INTEGER PRIMARY KEY,
+    persistent_id           TEXT NOT NULL,
+    parent_persistent_id    TEXT,
+    name                    TEXT NOT NULL,
+    folder                  INTEGER
+)";
+
+const PLAYLIST_ITEMS: &str = "CREATE TABLE playlist_items (
+    playlist_id INTEGER NOT NULL,
+    position    INTEGER NOT NULL,
+    track_id    INTEGER NOT NULL,
+    PRIMARY KEY (playlist_id, position)
+)";
+
+const LIBRARY_INFO: &str = "CREATE TABLE library_info (
+    id                      INTEGER PRIMARY KEY CHECK (id = 0),
+    library_persistent_id   TEXT,
+    date                    TEXT,
+    application_version     TEXT,
+    music_folder            TEXT,
+    major_version           INTEGER,
+    minor_version           INTEGER,
+    features                INTEGER,
+    show_content_ratings    INTEGER
+)";
+
+const LOCATION_RULES: &str = "CREATE TABLE location_rules (
+    position    INTEGER PRIMARY KEY,
+    source      TEXT,
+    target      TEXT NOT NULL
+)";
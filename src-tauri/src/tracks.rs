@@ -0,0 +1,321 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use itunes_xml::{Library, Track};
+
+use crate::playlists::load_playlists;
+
+/// Every `Track` field, in declaration order, read by [`track_from_row`].
+pub const TRACK_COLUMNS: &str = "\
+    id, name, artist, album_artist, composer, genre, album, kind, loved, disliked, matched, \
+    explicit, compilation, part_of_gapless_album, movie, podcast, unplayed, comments, \
+    content_rating, size, total_time, disc_number, disc_count, track_number, track_count, year, \
+    bpm, date_modified, date_added, bit_rate, sample_rate, equalizer, play_count, play_date, \
+    play_date_utc, skip_count, skip_date, release_date, normalization, rating, rating_computed, \
+    album_rating, album_rating_computed, artwork_count, sort_name, sort_album, \
+    sort_album_artist, sort_composer, sort_artist, persistent_id, track_type, purchased, \
+    music_video, has_video, hd, favorited, location, file_folder_count, library_folder_count, \
+    volume_adjustment, grouping, work, movement_name, movement_number, start_time, stop_time, \
+    extra, tempo_markers, cue_points";
+
+/// Columns the app owns once a track is stored: ratings, play history and cues. A re-import
+/// only fills them in while they are empty, so local edits survive it.
+const LOCAL_COLUMNS: [&str; 10] = [
+    "loved",
+    "disliked",
+    "play_count",
+    "play_date",
+    "play_date_utc",
+    "skip_count",
+    "skip_date",
+    "rating",
+    "tempo_markers",
+    "cue_points",
+];
+
+/// The track from `source` an imported one stands for: the one with the same persistent ID,
+/// or for sources without persistent IDs, the one at the same location.
+pub fn find_track(
+    conn: &Connection,
+    track: &Track,
+    source: &str,
+) -> rusqlite::Result<Option<Track>> {
+    let (condition, key) = match (&track.persistent_id, &track.location) {
+        (Some(persistent_id), _) => ("persistent_id = ?1", persistent_id),
+        (None, Some(location)) => ("persistent_id IS NULL AND location = ?1", location),
+        (None, None) => return Ok(None),
+    };
+    conn.prepare_cached(&format!(
+        "SELECT {} FROM tracks WHERE {} AND source = ?2 LIMIT 1",
+        TRACK_COLUMNS, condition
+    ))?
+    .query_row((key, source), track_from_row)
+    .optional()
+}
+
+/// `preferred` when no stored track has it, otherwise one past the highest stored ID.
+pub fn free_track_id(conn: &Connection, preferred: u64) -> rusqlite::Result<u64> {
+    conn.prepare_cached(
+        "SELECT CASE
+            WHEN EXISTS (SELECT 1 FROM tracks WHERE id = ?1) THEN (SELECT MAX(id) + 1 FROM tracks)
+            ELSE ?1
+        END",
+    )?
+    .query_row([preferred], |row| row.get(0))
+}
+
+pub fn insert_track(conn: &Connection, track: &Track) -> rusqlite::Result<()> {
+    let placeholders: Vec<String> = (1..=TRACK_COLUMNS.split(", ").count())
+        .map(|index| format!("?{}", index))
+        .collect();
+    let sql = format!(
+        "INSERT INTO tracks ({}) VALUES ({})",
+        TRACK_COLUMNS,
+        placeholders.join(", ")
+    );
+    execute_with_track(conn, &sql, track)
+}
+
+/// Overwrites the `Track` columns of the stored track with the same ID and clears its
+/// `removed` flag. [`LOCAL_COLUMNS`] are only written where the stored track has no value, and
+/// columns that are not part of `Track` are left alone.
+pub fn update_track(conn: &Connection, track: &Track) -> rusqlite::Result<()> {
+    let assignments: Vec<String> = TRACK_COLUMNS
+        .split(", ")
+        .enumerate()
+        .skip(1)
+        .map(|(index, column)| match LOCAL_COLUMNS.contains(&column) {
+            true => format!("{0} = COALESCE({0}, ?{1})", column, index + 1),
+            false => format!("{} = ?{}", column, index + 1),
+        })
+        .collect();
+    let sql = format!(
+        "UPDATE tracks SET {}, removed = 0 WHERE id = ?1",
+        assignments.join(", ")
+    );
+    execute_with_track(conn, &sql, track)
+}
+
+/// `track` with the locally owned fields of `stored` where it has them, the way
+/// [`update_track`] stores it.
+pub fn keep_local_fields(track: Track, stored: &Track) -> Track {
+    Track {
+        loved: stored.loved.or(track.loved),
+        disliked: stored.disliked.or(track.disliked),
+        play_count: stored.play_count.or(track.play_count),
+        play_date: stored.play_date.or(track.play_date),
+        play_date_utc: stored.play_date_utc.or(track.play_date_utc),
+        skip_count: stored.skip_count.or(track.skip_count),
+        skip_date: stored.skip_date.or(track.skip_date),
+        rating: stored.rating.or(track.rating),
+        tempo_markers: stored_or(&stored.tempo_markers, track.tempo_markers),
+        cue_points: stored_or(&stored.cue_points, track.cue_points),
+        ..track
+    }
+}
+
+/// Empty collections are stored as NULL, so the imported ones fill them in.
+fn stored_or<T: Clone>(stored: &[T], imported: Vec<T>) -> Vec<T> {
+    match stored.is_empty() {
+        true => imported,
+        false => stored.to_vec(),
+    }
+}
+
+/// Runs `sql` with the fields of `track` bound in [`TRACK_COLUMNS`] order. The statement is
+/// cached on the connection, imports run it once per track.
+fn execute_with_track(conn: &Connection, sql: &str, track: &Track) -> rusqlite::Result<()> {
+    conn.prepare_cached(sql)?.execute(params![
+        track.id,
+        &track.name,
+        &track.artist,
+        &track.album_artist,
+        &track.composer,
+        &track.genre,
+        &track.album,
+        &track.kind,
+        &track.loved,
+        &track.disliked,
+        &track.matched,
+        &track.explicit,
+        &track.compilation,
+        &track.part_of_gapless_album,
+        &track.movie,
+        &track.podcast,
+        &track.unplayed,
+        &track.comments,
+        &track.content_rating,
+        &track.size,
+        &track.total_time,
+        &track.disc_number,
+        &track.disc_count,
+        &track.track_number,
+        &track.track_count,
+        &track.year,
+        &track.bpm,
+        &track.date_modified,
+        &track.date_added,
+        &track.bit_rate,
+        &track.sample_rate,
+        &track.equalizer,
+        &track.play_count,
+        &track.play_date,
+        &track.play_date_utc,
+        &track.skip_count,
+        &track.skip_date,
+        &track.release_date,
+        &track.normalization,
+        &track.rating,
+        &track.rating_computed,
+        &track.album_rating,
+        &track.album_rating_computed,
+        &track.artwork_count,
+        &track.sort_name,
+        &track.sort_album,
+        &track.sort_album_artist,
+        &track.sort_composer,
+        &track.sort_artist,
+        &track.persistent_id,
+        &track.track_type,
+        &track.purchased,
+        &track.music_video,
+        &track.has_video,
+        &track.hd,
+        &track.favorited,
+        &track.location,
+        &track.file_folder_count,
+        &track.library_folder_count,
+        &track.volume_adjustment,
+        &track.grouping,
+        &track.work,
+        &track.movement_name,
+        &track.movement_number,
+        &track.start_time,
+        &track.stop_time,
+        json(&track.extra),
+        json(&track.tempo_markers),
+        json(&track.cue_points),
+    ])?;
+    Ok(())
+}
+
+pub fn track_from_row(row: &Row) -> rusqlite::Result<Track> {
+    Ok(Track {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        artist: row.get("artist")?,
+        album_artist: row.get("album_artist")?,
+        composer: row.get("composer")?,
+        genre: row.get("genre")?,
+        album: row.get("album")?,
+        kind: row.get("kind")?,
+        loved: row.get("loved")?,
+        disliked: row.get("disliked")?,
+        matched: row.get("matched")?,
+        explicit: row.get("explicit")?,
+        compilation: row.get("compilation")?,
+        part_of_gapless_album: row.get("part_of_gapless_album")?,
+        movie: row.get("movie")?,
+        podcast: row.get("podcast")?,
+        unplayed: row.get("unplayed")?,
+        comments: row.get("comments")?,
+        content_rating: row.get("content_rating")?,
+        size: row.get("size")?,
+        total_time: row.get("total_time")?,
+        disc_number: row.get("disc_number")?,
+        disc_count: row.get("disc_count")?,
+        track_number: row.get("track_number")?,
+        track_count: row.get("track_count")?,
+        year: row.get("year")?,
+        bpm: row.get("bpm")?,
+        date_modified: row.get("date_modified")?,
+        date_added: row.get("date_added")?,
+        bit_rate: row.get("bit_rate")?,
+        sample_rate: row.get("sample_rate")?,
+        equalizer: row.get("equalizer")?,
+        play_count: row.get("play_count")?,
+        play_date: row.get("play_date")?,
+        play_date_utc: row.get("play_date_utc")?,
+        skip_count: row.get("skip_count")?,
+        skip_date: row.get("skip_date")?,
+        release_date: row.get("release_date")?,
+        normalization: row.get("normalization")?,
+        rating: row.get("rating")?,
+        rating_computed: row.get("rating_computed")?,
+        album_rating: row.get("album_rating")?,
+        album_rating_computed: row.get("album_rating_computed")?,
+        artwork_count: row.get("artwork_count")?,
+        sort_name: row.get("sort_name")?,
+        sort_album: row.get("sort_album")?,
+        sort_album_artist: row.get("sort_album_artist")?,
+        sort_composer: row.get("sort_composer")?,
+        sort_artist: row.get("sort_artist")?,
+        persistent_id: row.get("persistent_id")?,
+        track_type: row.get("track_type")?,
+        purchased: row.get("purchased")?,
+        music_video: row.get("music_video")?,
+        has_video: row.get("has_video")?,
+        hd: row.get("hd")?,
+        favorited: row.get("favorited")?,
+        location: row.get("location")?,
+        file_folder_count: row.get("file_folder_count")?,
+        library_folder_count: row.get("library_folder_count")?,
+        volume_adjustment: row.get("volume_adjustment")?,
+        grouping: row.get("grouping")?,
+        work: row.get("work")?,
+        movement_name: row.get("movement_name")?,
+        movement_number: row.get("movement_number")?,
+        start_time: row.get("start_time")?,
+        stop_time: row.get("stop_time")?,
+        extra: json_column(row, "extra")?,
+        tempo_markers: json_column(row, "tempo_markers")?,
+        cue_points: json_column(row, "cue_points")?,
+    })
+}
+
+/// Reads the stored tracks and playlists back into a `Library`, without the tracks the last
+/// import no longer had.
+pub fn load_library(conn: &Connection) -> rusqlite::Result<Library> {
+    let mut library = Library::default();
+    let mut statement = conn.prepare(&format!(
+        "SELECT {} FROM tracks WHERE removed = 0",
+        TRACK_COLUMNS
+    ))?;
+    for track in statement.query_map((), track_from_row)? {
+        let track = track?;
+        library.tracks.insert(track.id, track);
+    }
+    for playlist in load_playlists(conn)? {
+        library.playlists.insert(playlist.id, playlist);
+    }
+    Ok(library)
+}
+
+/// The stored tracks from `source` the last import found, by ID.
+pub fn load_source_tracks(conn: &Connection, source: &str) -> rusqlite::Result<Vec<Track>> {
+    conn.prepare_cached(&format!(
+        "SELECT {} FROM tracks WHERE removed = 0 AND source = ?1 ORDER BY id",
+        TRACK_COLUMNS
+    ))?
+    .query_map([source], track_from_row)?
+    .collect()
+}
+
+/// Serializes a collection for a JSON column, `None` when it is empty.
+fn json<T: Serialize>(value: &T) -> Option<String> {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::Array(items)) if items.is_empty() => None,
+        Ok(serde_json::Value::Object(fields)) if fields.is_empty() => None,
+        Ok(value) => Some(value.to_string()),
+        Err(_) => None,
+    }
+}
+
+/// Values serialized with serde_json, empty when the column is NULL.
+fn json_column<T: DeserializeOwned + Default>(row: &Row, column: &str) -> rusqlite::Result<T> {
+    Ok(row
+        .get::<_, Option<String>>(column)?
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default())
+}
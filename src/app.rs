@@ -1,42 +1,92 @@
 use std::collections::VecDeque;
+use std::future::Future;
 use std::path::PathBuf;
 
+use futures::future::{self, Either};
+use futures::{pin_mut, StreamExt};
 use leptos::*;
 use leptos::ev::MouseEvent;
 use serde::Serialize;
 use tauri_sys::dialog::FileDialogBuilder;
-use tauri_sys::tauri;
+use tauri_sys::{event, tauri};
 
-use itunes_xml::Track;
-use types::QueryParams;
+use itunes_xml::{Diagnostic, LibraryInfo, LocationRule, ParseReport, PlaylistNode, Track};
+use types::{ImportProgress, LibraryFormat, LocationReport, QueryParams};
 
-async fn pick_file() -> Result<Option<PathBuf>, String> {
+async fn pick_file(format: LibraryFormat) -> Result<Option<PathBuf>, String> {
     FileDialogBuilder::new()
         .set_title("Select a file to mark this test as passing")
+        .add_filter(format.name(), format.extensions())
         .pick_file()
         .await
         .map_err(|e| e.to_string())
 }
 
+async fn pick_folder() -> Result<Option<PathBuf>, String> {
+    FileDialogBuilder::new()
+        .set_title("Select a music folder")
+        .pick_folder()
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[derive(Serialize)]
 struct ParseCommandArgs<'a> {
     path: &'a str,
 }
 
+#[derive(Serialize)]
+struct ParseLibraryArgs<'a> {
+    path: &'a str,
+    format: LibraryFormat,
+}
+
 #[derive(Serialize)]
 struct PlayTrackArgs<'a> {
     path: &'a str,
 }
 
-async fn parse_itunes_xml(lib_path: String) -> Result<(), String> {
+async fn parse_itunes_xml(
+    lib_path: String,
+    format: LibraryFormat,
+) -> Result<(String, ParseReport), String> {
     tauri::invoke(
         "parse_itunes_xml_command",
-        &ParseCommandArgs { path: &lib_path },
+        &ParseLibraryArgs { path: &lib_path, format },
+    )
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn scan_folder(folder_path: String) -> Result<String, String> {
+    tauri::invoke(
+        "scan_folder_command",
+        &ParseCommandArgs { path: &folder_path },
     )
         .await
         .map_err(|e| e.to_string())
 }
 
+/// Runs an import command, passing its `import-progress` events to `set_progress` until it
+/// returns.
+async fn with_import_progress<T>(
+    import: impl Future<Output = Result<T, String>>,
+    set_progress: WriteSignal<Option<ImportProgress>>,
+) -> Result<T, String> {
+    let events = event::listen::<ImportProgress>("import-progress")
+        .await
+        .map_err(|e| e.to_string())?;
+    let updates = events.for_each(|event| {
+        set_progress.set(Some(event.payload));
+        future::ready(())
+    });
+    pin_mut!(import, updates);
+    match future::select(import, updates).await {
+        Either::Left((result, _)) => result,
+        Either::Right(((), import)) => import.await,
+    }
+}
+
 async fn play_track(lib_path: &str) -> Result<(), String> {
     tauri::invoke("play_track_command", &PlayTrackArgs { path: lib_path })
         .await
@@ -63,26 +113,10 @@ struct QueryParamsArgs<'a> {
     query: QueryParams<'a>,
 }
 
-async fn fetch_tracks(
-    limit: usize,
-    title: Option<&str>,
-    artist: Option<&str>,
-    bpm_min: Option<i64>,
-    bpm_max: Option<i64>,
-    location: Option<&str>,
-) -> Result<Vec<Track>, String> {
+async fn fetch_tracks(query: QueryParams<'_>) -> Result<Vec<Track>, String> {
     tauri::invoke(
         "fetch_tracks_command",
-        &QueryParamsArgs {
-            query: QueryParams {
-                limit,
-                title,
-                artist,
-                bpm_min,
-                bpm_max,
-                location,
-            },
-        },
+        &QueryParamsArgs { query },
     )
         .await
         .map_err(|e| e.to_string())
@@ -97,6 +131,90 @@ async fn fetch_library_loaded() -> Result<bool, String> {
         .map_err(|e| e.to_string())
 }
 
+async fn fetch_library_info() -> Result<Option<LibraryInfo>, String> {
+    tauri::invoke("library_info_command", &NoArgs {})
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn fetch_playlist_tree() -> Result<Vec<PlaylistNode>, String> {
+    tauri::invoke("playlist_tree_command", &NoArgs {})
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistArgs {
+    playlist_id: u64,
+}
+
+async fn fetch_playlist_tracks(playlist_id: u64) -> Result<Vec<Track>, String> {
+    tauri::invoke("fetch_playlist_tracks_command", &PlaylistArgs { playlist_id })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Serialize)]
+struct LocationRulesArgs<'a> {
+    rules: &'a [LocationRule],
+}
+
+async fn fetch_location_rules() -> Result<Vec<LocationRule>, String> {
+    tauri::invoke("location_rules_command", &NoArgs {})
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn save_location_rules(rules: &[LocationRule]) -> Result<(), String> {
+    tauri::invoke("set_location_rules_command", &LocationRulesArgs { rules })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn check_locations(rules: &[LocationRule]) -> Result<LocationReport, String> {
+    tauri::invoke("check_locations_command", &LocationRulesArgs { rules })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportPlaylistsArgs<'a> {
+    playlist_ids: &'a [u64],
+}
+
+async fn export_rekordbox(playlist_ids: &[u64]) -> Result<Option<String>, String> {
+    tauri::invoke("export_rekordbox_command", &ExportPlaylistsArgs { playlist_ids })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn import_playlist_file() -> Result<Option<String>, String> {
+    tauri::invoke("import_playlist_file_command", &NoArgs {})
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportPlaylistFileArgs {
+    playlist_id: u64,
+    relative: bool,
+}
+
+async fn export_playlist_file(playlist_id: u64, relative: bool) -> Result<Option<String>, String> {
+    tauri::invoke(
+        "export_playlist_file_command",
+        &ExportPlaylistFileArgs {
+            playlist_id,
+            relative,
+        },
+    )
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[component]
 pub fn App() -> impl IntoView {
     let library_fetched = create_resource(
@@ -104,6 +222,9 @@ pub fn App() -> impl IntoView {
         |_| async move { fetch_library_loaded().await },
     );
 
+    let (import_summary, set_import_summary) = create_signal(String::default());
+    let (import_diagnostics, set_import_diagnostics) = create_signal(Vec::<Diagnostic>::new());
+
     let contents = move || match library_fetched.get() {
         None => view! {
             <p>"Loading..."</p>}.into_view(),
@@ -112,32 +233,68 @@ pub fn App() -> impl IntoView {
         Some(Ok(true)) => view! {
             <LibraryView/>}.into_view(),
         Some(Ok(false)) => view! {
-            <ChooseLibrary library_fetched=library_fetched />}.into_view(),
+            <ChooseLibrary
+                library_fetched=library_fetched
+                set_import_summary=set_import_summary
+                set_import_diagnostics=set_import_diagnostics
+            />}.into_view(),
+    };
+
+    let diagnostics_view = move || {
+        import_diagnostics
+            .get()
+            .into_iter()
+            .map(|diagnostic| {
+                let text = match diagnostic.track_id {
+                    Some(id) => {
+                        format!("Line {}, track {}: {}", diagnostic.line, id, diagnostic.reason)
+                    }
+                    None => format!("Line {}: {}", diagnostic.line, diagnostic.reason),
+                };
+                view! { <li>{text}</li> }
+            })
+            .collect_view()
     };
 
     view! {
         <main class="container">
+            <p class="status">{ move || import_summary.get() }</p>
+            <ul class="diagnostics">{ diagnostics_view }</ul>
             { contents }
         </main>
     }
 }
 
 #[component]
-fn ChooseLibrary(library_fetched: Resource<(), Result<bool, String>>) -> impl IntoView {
+fn ChooseLibrary(
+    library_fetched: Resource<(), Result<bool, String>>,
+    set_import_summary: WriteSignal<String>,
+    set_import_diagnostics: WriteSignal<Vec<Diagnostic>>,
+) -> impl IntoView {
     let (status, set_status) = create_signal(String::default());
+    let (progress, set_progress) = create_signal(None::<ImportProgress>);
+    let (format, set_format) = create_signal(LibraryFormat::Itunes);
 
     let choose_file = move |ev: MouseEvent| {
         ev.prevent_default();
+        let format = format.get_untracked();
 
         spawn_local(async move {
-            match pick_file().await {
+            match pick_file(format).await {
                 Ok(Some::<PathBuf>(f)) => {
                     let picked_file = f.to_string_lossy().to_string();
                     set_status.set("Loading library file...".to_string());
 
                     spawn_local(async move {
-                        match parse_itunes_xml(picked_file).await {
-                            Ok(_) => library_fetched.refetch(),
+                        let import = parse_itunes_xml(picked_file, format);
+                        let result = with_import_progress(import, set_progress).await;
+                        set_progress.set(None);
+                        match result {
+                            Ok((summary, report)) => {
+                                set_import_summary.set(summary);
+                                set_import_diagnostics.set(report.diagnostics);
+                                library_fetched.refetch()
+                            }
                             Err(e) => set_status.set(e),
                         };
                     });
@@ -148,11 +305,67 @@ fn ChooseLibrary(library_fetched: Resource<(), Result<bool, String>>) -> impl In
         });
     };
 
+    let choose_folder = move |ev: MouseEvent| {
+        ev.prevent_default();
+
+        spawn_local(async move {
+            match pick_folder().await {
+                Ok(Some(folder)) => {
+                    set_status.set("Reading tags...".to_string());
+                    let scan = scan_folder(folder.to_string_lossy().to_string());
+                    let result = with_import_progress(scan, set_progress).await;
+                    set_progress.set(None);
+                    match result {
+                        Ok(summary) => {
+                            set_import_summary.set(summary);
+                            library_fetched.refetch()
+                        }
+                        Err(e) => set_status.set(e),
+                    };
+                }
+                Ok(None) => set_status.set(String::default()),
+                Err(e) => set_status.set(e),
+            };
+        });
+    };
+
+    let format_options = LibraryFormat::ALL
+        .iter()
+        .enumerate()
+        .map(|(index, format)| view! { <option value={index.to_string()}>{format.name()}</option> })
+        .collect_view();
+
+    // Without a known size the bar shows activity only
+    let progress_view = move || {
+        progress.get().map(|progress| {
+            let counts = format!(
+                "{} tracks read, {} imported, {} playlists",
+                progress.tracks_parsed, progress.tracks_imported, progress.playlists_imported
+            );
+            view! {
+                <div class="import-progress">
+                    <progress max="100" value=progress.percent></progress>
+                    <p>{counts}</p>
+                </div>
+            }
+        })
+    };
+
     view! {
         <div class="pick-file">
             <p class="status"><b>{ move || status.get() }</b></p>
+            { progress_view }
 
+            <select on:change=move |ev| {
+                let index = event_target_value(&ev).parse::<usize>().ok();
+                if let Some(format) = index.and_then(|index| LibraryFormat::ALL.get(index)) {
+                    set_format.set(*format);
+                }
+            }>
+                { format_options }
+            </select>
             <button on:click=choose_file>{"Choose Library"}</button>
+            <button on:click=choose_folder>{"Scan Music Folder"}</button>
         </div>
     }
 }
@@ -160,9 +373,26 @@ fn ChooseLibrary(library_fetched: Resource<(), Result<bool, String>>) -> impl In
 #[component]
 fn LibraryView() -> impl IntoView {
     let (queue, set_queue) = create_signal(VecDeque::<Track>::default());
+    let library_info = create_resource(|| (), |_| async move { fetch_library_info().await });
+
+    let info_view = move || match library_info.get() {
+        Some(Ok(Some(info))) => {
+            let id = info.library_persistent_id.unwrap_or_default();
+            let date = info
+                .date
+                .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            view! { <p class="library-info">"Library " {id} " exported " {date}</p> }.into_view()
+        }
+        Some(Err(e)) => view! { <p>"Error: " {e}</p> }.into_view(),
+        _ => ().into_view(),
+    };
 
     view! {
         <div class="main">
+            { info_view }
+            <PlaylistFolders set_queue=set_queue/>
+            <LocationRules/>
             <TracksTable set_queue=set_queue/>
         </div>
 
@@ -172,11 +402,212 @@ fn LibraryView() -> impl IntoView {
     }
 }
 
+#[component]
+fn PlaylistFolders(set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoView {
+    let playlist_tree = create_resource(|| (), |_| async move { fetch_playlist_tree().await });
+    let (relative, set_relative) = create_signal(false);
+    let (status, set_status) = create_signal(String::default());
+
+    let on_import = move |ev: MouseEvent| {
+        ev.prevent_default();
+        spawn_local(async move {
+            match import_playlist_file().await {
+                Ok(Some(summary)) => {
+                    set_status.set(summary);
+                    playlist_tree.refetch();
+                }
+                Ok(None) => set_status.set(String::default()),
+                Err(e) => set_status.set(e),
+            }
+        });
+    };
+
+    let nodes = move || match playlist_tree.get() {
+        None => ().into_view(),
+        Some(Ok(nodes)) => view! {
+            <ul class="playlists">
+                {
+                    nodes
+                        .into_iter()
+                        .map(|node| playlist_node(node, relative, set_queue))
+                        .collect_view()
+                }
+            </ul>
+        }.into_view(),
+        Some(Err(e)) => view! { <p>"Error: " {e}</p> }.into_view(),
+    };
+
+    view! {
+        <div class="playlist-files">
+            <button on:click=on_import>"Import playlist"</button>
+            <label>
+                <input
+                    type="checkbox"
+                    on:change=move |ev| set_relative.set(event_target_checked(&ev))
+                    prop:checked={move || relative.get()}
+                />
+                "Relative paths on export"
+            </label>
+            <span>{move || status.get()}</span>
+        </div>
+        { nodes }
+    }
+}
+
+fn playlist_node(
+    node: PlaylistNode,
+    relative: ReadSignal<bool>,
+    set_queue: WriteSignal<VecDeque<Track>>,
+) -> View {
+    let children = node
+        .children
+        .into_iter()
+        .map(|child| playlist_node(child, relative, set_queue))
+        .collect_view();
+    let label = format!("{} ({} tracks)", node.name, node.track_ids.len());
+    let (status, set_status) = create_signal(String::default());
+    let id = node.id;
+    let exported = move |result: Result<Option<String>, String>| match result {
+        Ok(Some(path)) => set_status.set(format!("Exported to {}", path)),
+        Ok(None) => set_status.set(String::default()),
+        Err(e) => set_status.set(e),
+    };
+    let on_export = move |ev: MouseEvent| {
+        ev.prevent_default();
+        spawn_local(async move { exported(export_rekordbox(&[id]).await) });
+    };
+    let on_export_file = move |ev: MouseEvent| {
+        ev.prevent_default();
+        spawn_local(async move { exported(export_playlist_file(id, relative.get()).await) });
+    };
+    // Replaces the queue with the playlist, in playlist order
+    let on_load = move |ev: MouseEvent| {
+        ev.prevent_default();
+        spawn_local(async move {
+            match fetch_playlist_tracks(id).await {
+                Ok(tracks) => set_queue.set(tracks.into()),
+                Err(e) => set_status.set(e),
+            }
+        });
+    };
+    let export = view! {
+        <button on:click=on_export title="Export to Rekordbox">"Export"</button>
+        <button on:click=on_export_file title="Export as M3U or PLS">"Save as..."</button>
+        <span>{move || status.get()}</span>
+    };
+    match node.folder {
+        true => view! {
+            <li>
+                <details>
+                    <summary>{label} {export}</summary>
+                    <ul>{children}</ul>
+                </details>
+            </li>
+        }.into_view(),
+        false => view! {
+            <li>
+                {label}
+                <button on:click=on_load title="Queue this playlist">"Load"</button>
+                {export}
+            </li>
+        }.into_view(),
+    }
+}
+
+#[component]
+fn LocationRules() -> impl IntoView {
+    let (rules, set_rules) = create_signal(Vec::<LocationRule>::new());
+    let (from, set_from) = create_signal(String::default());
+    let (to, set_to) = create_signal(String::default());
+    let (status, set_status) = create_signal(String::default());
+
+    spawn_local(async move {
+        match fetch_location_rules().await {
+            Ok(saved) => set_rules.set(saved),
+            Err(e) => set_status.set(e),
+        }
+    });
+
+    let on_add = move |ev: MouseEvent| {
+        ev.prevent_default();
+        let from = match from.get() {
+            from if from.is_empty() => None,
+            from => Some(from),
+        };
+        set_rules.update(|rules| rules.push(LocationRule { from, to: to.get() }));
+        set_from.set(String::default());
+        set_to.set(String::default());
+    };
+
+    let on_check = move |ev: MouseEvent| {
+        ev.prevent_default();
+        spawn_local(async move {
+            match check_locations(&rules.get()).await {
+                Ok(report) => set_status.set(format!(
+                    "{} of {} locations resolve, {} remapped{}",
+                    report.resolved,
+                    report.total,
+                    report.remapped,
+                    report
+                        .missing
+                        .first()
+                        .map(|location| format!(", e.g. missing {}", location))
+                        .unwrap_or_default(),
+                )),
+                Err(e) => set_status.set(e),
+            }
+        })
+    };
+
+    let on_save = move |ev: MouseEvent| {
+        ev.prevent_default();
+        spawn_local(async move {
+            match save_location_rules(&rules.get()).await {
+                Ok(_) => set_status.set("Location rules saved".to_string()),
+                Err(e) => set_status.set(e),
+            }
+        })
+    };
+
+    let rule_view = move |(index, rule): (usize, LocationRule)| {
+        let from = rule.from.unwrap_or_else(|| "Music Folder".to_string());
+        view! {
+            <li>
+                {from} " → " {rule.to}
+                <button on:click=move |_| set_rules.update(|rules| { rules.remove(index); })>
+                    "-"
+                </button>
+            </li>
+        }
+    };
+
+    view! {
+        <details class="location-rules">
+            <summary>"Location remapping"</summary>
+            <ul>{ move || rules.get().into_iter().enumerate().map(rule_view).collect_view() }</ul>
+            <input type="text" placeholder="Music Folder"
+                on:input=move |ev| set_from.set(event_target_value(&ev))
+                prop:value={move || from.get()}
+            />
+            <input type="text" placeholder="file:///home/me/Music/"
+                on:input=move |ev| set_to.set(event_target_value(&ev))
+                prop:value={move || to.get()}
+            />
+            <button on:click=on_add>"Add"</button>
+            <button on:click=on_check>"Check"</button>
+            <button on:click=on_save>"Save"</button>
+            <p>{ move || status.get() }</p>
+        </details>
+    }
+}
+
 #[derive(Clone, PartialEq, Default, Debug)]
 struct State {
     limit: String,
     title: String,
     artist: String,
+    album: String,
+    genre: String,
     bpm_min: String,
     bpm_max: String,
     location: String,
@@ -195,6 +626,16 @@ fn TracksTable(set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoView {
         |state| state.artist.clone(),
         |state, v| state.artist = v,
     );
+    let (album_filter, set_album_filter) = create_slice(
+        state,
+        |state| state.album.clone(),
+        |state, v| state.album = v,
+    );
+    let (genre_filter, set_genre_filter) = create_slice(
+        state,
+        |state| state.genre.clone(),
+        |state, v| state.genre = v,
+    );
     let (bpm_min_filter, set_bpm_min_filter) = create_slice(
         state,
         |state| state.bpm_min.clone(),
@@ -218,7 +659,12 @@ fn TracksTable(set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoView {
                 <th>{"Track ID"}</th>
                 <th>{"Name"}</th>
                 <th>{"Artist"}</th>
+                <th>{"Album"}</th>
+                <th>{"Genre"}</th>
                 <th>{"BPM"}</th>
+                <th>{"Time"}</th>
+                <th>{"Year"}</th>
+                <th>{"Rating"}</th>
                 <th>{"Location"}</th>
             </tr>
 
@@ -241,6 +687,22 @@ fn TracksTable(set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoView {
                         prop:value={move || artist_filter.get()}
                     />
                 </th>
+                <th>
+                    <input type="text"
+                        on:input=move |ev| {
+                            set_album_filter.set(event_target_value(&ev));
+                        }
+                        prop:value={move || album_filter.get()}
+                    />
+                </th>
+                <th>
+                    <input type="text"
+                        on:input=move |ev| {
+                            set_genre_filter.set(event_target_value(&ev));
+                        }
+                        prop:value={move || genre_filter.get()}
+                    />
+                </th>
                 <th>
                     <input type="number" min="1" max="500"
                         on:input=move |ev| {
@@ -255,6 +717,9 @@ fn TracksTable(set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoView {
                         prop:value={move || bpm_max_filter.get()}
                     />
                 </th>
+                <th></th>
+                <th></th>
+                <th></th>
                 <th>
                     <input type="text"
                         on:input=move |ev| {
@@ -289,13 +754,31 @@ fn TracksComponent(
                 "" => None,
                 s => Some(s),
             };
+            let album = match value.album.as_str() {
+                "" => None,
+                s => Some(s),
+            };
+            let genre = match value.genre.as_str() {
+                "" => None,
+                s => Some(s),
+            };
             let bpm_min = value.bpm_min.parse::<i64>().ok();
             let bpm_max = value.bpm_max.parse::<i64>().ok();
             let location = match value.location.as_str() {
                 "" => None,
                 s => Some(s),
             };
-            fetch_tracks(100, title, artist, bpm_min, bpm_max, location).await
+            fetch_tracks(QueryParams {
+                limit: 100,
+                title,
+                artist,
+                album,
+                genre,
+                bpm_min,
+                bpm_max,
+                location,
+            })
+                .await
         },
     );
 
@@ -315,6 +798,11 @@ fn TracksComponent(
 #[component]
 fn TrackRow(track: Track, set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoView {
     let track_clone = track.clone();
+    let duration = track
+        .total_time
+        .map(|millis| format!("{}:{:02}", millis / 60_000, millis / 1000 % 60));
+    // Ratings are 0-100 in steps of 20
+    let rating = track.rating.map(|rating| "★".repeat((rating / 20) as usize));
     view! {
         <tr>
             <td>
@@ -329,7 +817,12 @@ fn TrackRow(track: Track, set_queue: WriteSignal<VecDeque<Track>>) -> impl IntoV
             <td>{track.id}</td>
             <td>{track.name}</td>
             <td>{track.artist}</td>
+            <td>{track.album}</td>
+            <td>{track.genre}</td>
             <td>{track.bpm}</td>
+            <td>{duration}</td>
+            <td>{track.year}</td>
+            <td>{rating}</td>
             <td>{track.location}</td>
         </tr>
     }
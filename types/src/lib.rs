@@ -5,6 +5,8 @@ pub struct QueryParams<'a> {
     pub limit: usize,
     pub title: Option<&'a str>,
     pub artist: Option<&'a str>,
+    pub album: Option<&'a str>,
+    pub genre: Option<&'a str>,
     pub bpm_min: Option<i64>,
     pub bpm_max: Option<i64>,
     pub location: Option<&'a str>,
@@ -16,9 +18,66 @@ impl<'a> Default for QueryParams<'a> {
             limit: 100,
             title: None,
             artist: None,
+            album: None,
+            genre: None,
             bpm_min: None,
             bpm_max: None,
             location: None,
         }
     }
 }
+
+/// Outcome of checking track locations against the remapping rules, without changing them.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct LocationReport {
+    pub total: usize,
+    pub remapped: usize,
+    /// Locations pointing to an existing file once remapped.
+    pub resolved: usize,
+    /// The first locations that do not resolve, as remapped.
+    pub missing: Vec<String>,
+}
+
+/// Emitted as `import-progress` while a library import runs.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ImportProgress {
+    /// Share of the source read so far, `None` when its size is unknown.
+    pub percent: Option<f64>,
+    pub tracks_parsed: usize,
+    pub tracks_imported: usize,
+    pub playlists_imported: usize,
+}
+
+/// Export format of a library file, chosen in the import dialog.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LibraryFormat {
+    /// iTunes or Music XML, or the binary plist of either.
+    Itunes,
+    Rekordbox,
+    Traktor,
+}
+
+impl LibraryFormat {
+    pub const ALL: [LibraryFormat; 3] = [
+        LibraryFormat::Itunes,
+        LibraryFormat::Rekordbox,
+        LibraryFormat::Traktor,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LibraryFormat::Itunes => "iTunes library",
+            LibraryFormat::Rekordbox => "Rekordbox collection",
+            LibraryFormat::Traktor => "Traktor collection",
+        }
+    }
+
+    /// File extensions the file dialog offers.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            LibraryFormat::Itunes => &["xml", "plist"],
+            LibraryFormat::Rekordbox => &["xml"],
+            LibraryFormat::Traktor => &["nml"],
+        }
+    }
+}